@@ -14,6 +14,10 @@
 
 (defvar idfind-command "idfind " "The command run by the idfind function.")
 
+(defvar idfind-connect nil
+  "The address of the idfind server, like \"127.0.0.1:4141\".
+If nil, idfind uses $IDFIND_ADDR, the addr of its config file or its default.")
+
 (defvar idfind-mode-font-lock-keywords
   '(("^\\(Compilation\\|idfind\\) \\(started\\|finished\\).*"
      (0 '(face nil message nil help-echo nil mouse-face nil) t))))
@@ -38,7 +42,9 @@ defined by the idfind-command variable."
 	(compilation-directory default-directory)
 	(idfind-full-buffer-name (concat "*idfind-buf*")))
     (save-some-buffers (not compilation-ask-about-save) nil)
-    (compilation-start (concat idfind-command "-m search -d " (projectile-project-root) "sdb.json" " -e \"" args "\"") 'idfind-mode
+    (compilation-start (concat idfind-command "-m search -p " (projectile-project-root)
+                               (if idfind-connect (concat " --connect " idfind-connect) "")
+                               " -e \"" args "\"") 'idfind-mode
 		         (function (lambda (ignore)
 		        	     idfind-full-buffer-name))
 		       (regexp-quote args))))
@@ -3,12 +3,27 @@ use clap::builder::ArgAction;
 
 use std::path::{PathBuf, Path};
 
+use crate::utils::{default_db_path, find_default_db, server_addr};
+use crate::matcher::{Case, MatchOptions};
+use crate::output::{Format, PrintOptions, Sort, MAX_WIDTH};
+
 pub struct CLIArgs {
     pub mode: String,
     pub project: PathBuf,
     pub database: String,
+    pub output: Option<PathBuf>,
     pub expr: String,
-    pub include_ext: Vec<String>
+    pub matching: MatchOptions,
+    pub printing: PrintOptions,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub offset: usize,
+    pub limit: Option<usize>,
+    pub include_ext: Vec<String>,
+    pub no_ignore: bool,
+    pub watch: bool,
+    pub persist: bool,
+    pub addr: String,
 }
 
 impl CLIArgs {
@@ -22,7 +37,7 @@ impl CLIArgs {
                     .long("mode")
                     .short('m')
                     .action(ArgAction::Set)
-                    .value_parser(["index", "cli", "server", "search"])
+                    .value_parser(["index", "update", "cli", "server", "search", "convert"])
                     .required(true)
                     .help("Which mode to run in")
             )
@@ -33,6 +48,12 @@ impl CLIArgs {
                     .value_delimiter(',')
                     .help("extensions to include in db. Accepts a list separated by ','")
             )
+            .arg(
+                Arg::new("no-ignore")
+                    .long("no-ignore")
+                    .action(ArgAction::SetTrue)
+                    .help("Also index the files ignored by .gitignore, .ignore and the git exclude files")
+            )
             .arg(
                 Arg::new("project")
                     .long("project")
@@ -40,16 +61,25 @@ impl CLIArgs {
                     .required_if_eq("mode", "index")
                     .action(ArgAction::Set)
                     .value_name("project-root")
-                    .help("Path of the project root which is to be indexed")
+                    .help("Path of the project root which is to be indexed. In the other \
+                           modes, the project whose default database is to be used")
             )
             .arg(
                 Arg::new("database")
                     .long("database")
                     .short('d')
                     .action(ArgAction::Set)
-                    .required_if_eq("mode", "cli")
-                    .required_if_eq("mode", "search")
-                    .help("The database to load")
+                    .required_if_eq("mode", "convert")
+                    .help("The database to load. Defaults to the database of the project \
+                           (see --project), or of the current dir or any of its parents")
+            )
+            .arg(
+                Arg::new("output")
+                    .long("output")
+                    .short('o')
+                    .action(ArgAction::Set)
+                    .help("Where to save the database. Defaults to a file named after the \
+                           project in $XDG_CACHE_HOME/idfind")
             )
             .arg(
                 Arg::new("expression")
@@ -59,6 +89,219 @@ impl CLIArgs {
                     .required_if_eq("mode", "search")
                     .help("The term to search for")
             )
+            .arg(
+                Arg::new("regex")
+                    .long("regex")
+                    .short('r')
+                    .action(ArgAction::SetTrue)
+                    .help("Treat the search term as a regular expression, which is \
+                           matched against every line")
+            )
+            .arg(
+                Arg::new("boolean")
+                    .long("boolean")
+                    .short('b')
+                    .action(ArgAction::SetTrue)
+                    .help("Treat the search term as a boolean query of terms combined \
+                           with AND, OR, NOT (or a leading -) and parentheses")
+            )
+            .arg(
+                Arg::new("word")
+                    .long("word-regexp")
+                    .short('w')
+                    .action(ArgAction::SetTrue)
+                    .help("Only match whole words, which are not part of a larger \
+                           identifier of the language of the file")
+            )
+            .arg(
+                Arg::new("ignore-case")
+                    .long("ignore-case")
+                    .short('i')
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with("case-sensitive")
+                    .help("Ignore the case of the letters in the search term. By default \
+                           the case is only ignored if the search term is all lowercase")
+            )
+            .arg(
+                Arg::new("case-sensitive")
+                    .long("case-sensitive")
+                    .short('s')
+                    .action(ArgAction::SetTrue)
+                    .help("Match the case of the letters in the search term exactly")
+            )
+            .arg(
+                Arg::new("after-context")
+                    .long("after-context")
+                    .short('A')
+                    .action(ArgAction::Set)
+                    .value_name("NUM")
+                    .value_parser(clap::value_parser!(usize))
+                    .help("Print NUM lines of context after every match")
+            )
+            .arg(
+                Arg::new("before-context")
+                    .long("before-context")
+                    .short('B')
+                    .action(ArgAction::Set)
+                    .value_name("NUM")
+                    .value_parser(clap::value_parser!(usize))
+                    .help("Print NUM lines of context before every match")
+            )
+            .arg(
+                Arg::new("context")
+                    .long("context")
+                    .short('C')
+                    .action(ArgAction::Set)
+                    .value_name("NUM")
+                    .value_parser(clap::value_parser!(usize))
+                    .help("Print NUM lines of context before and after every match. \
+                           -A and -B take precedence over this")
+            )
+            .arg(
+                Arg::new("sort")
+                    .long("sort")
+                    .action(ArgAction::Set)
+                    .value_parser(["path", "rank"])
+                    .default_value("path")
+                    .help("The order of the files in the results: by path, or with the \
+                           files with the most matching lines first")
+            )
+            .arg(
+                Arg::new("max-width")
+                    .long("max-width")
+                    .action(ArgAction::Set)
+                    .value_name("NUM")
+                    .value_parser(clap::value_parser!(usize))
+                    .help("Cut the lines longer than NUM bytes down to snippets around \
+                           the matches (100 by default). 0 always prints the lines in full")
+            )
+            .arg(
+                Arg::new("vimgrep")
+                    .long("vimgrep")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with_all(["json", "files-with-matches", "count"])
+                    .help("Print every match as path:line:column:text, for the Vim \
+                           quickfix list and other tools")
+            )
+            .arg(
+                Arg::new("files-with-matches")
+                    .long("files-with-matches")
+                    .short('l')
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with_all(["json", "count"])
+                    .help("Only print the paths of the files with a match")
+            )
+            .arg(
+                Arg::new("count")
+                    .long("count")
+                    .short('c')
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with("json")
+                    .help("Only print the number of matching lines of every file with a match")
+            )
+            .arg(
+                Arg::new("null")
+                    .long("null")
+                    .short('0')
+                    .action(ArgAction::SetTrue)
+                    .help("End the paths with a NUL byte instead of ':' or a newline")
+            )
+            .arg(
+                Arg::new("max-count")
+                    .long("max-count")
+                    .action(ArgAction::Set)
+                    .value_name("NUM")
+                    .value_parser(clap::value_parser!(usize))
+                    .help("Stop looking for matches in a file after NUM matching lines")
+            )
+            .arg(
+                Arg::new("max-results")
+                    .long("max-results")
+                    .action(ArgAction::Set)
+                    .value_name("NUM")
+                    .value_parser(clap::value_parser!(usize))
+                    .help("Stop the search after NUM matching lines in total")
+            )
+            .arg(
+                Arg::new("include")
+                    .long("include")
+                    .action(ArgAction::Append)
+                    .value_name("GLOB")
+                    .help("Only search the files whose path matches GLOB, like `*.h` or \
+                           `src/net/`. Can be given more than once")
+            )
+            .arg(
+                Arg::new("exclude")
+                    .long("exclude")
+                    .action(ArgAction::Append)
+                    .value_name("GLOB")
+                    .help("Skip the files whose path matches GLOB, like `tests/`. Can be \
+                           given more than once")
+            )
+            .arg(
+                Arg::new("offset")
+                    .long("offset")
+                    .action(ArgAction::Set)
+                    .value_name("NUM")
+                    .value_parser(clap::value_parser!(usize))
+                    .default_value("0")
+                    .help("In search mode, skip the first NUM files which can contain a \
+                           match, to page through the results")
+            )
+            .arg(
+                Arg::new("limit")
+                    .long("limit")
+                    .action(ArgAction::Set)
+                    .value_name("NUM")
+                    .value_parser(clap::value_parser!(usize))
+                    .help("In search mode, only search NUM of the files which can contain \
+                           a match, starting from --offset")
+            )
+            .arg(
+                Arg::new("explain")
+                    .long("explain")
+                    .action(ArgAction::SetTrue)
+                    .help("Report how the index was used: the trigrams looked up, the \
+                           files left after each of them, how many of the files read had \
+                           a match and the time spent on every phase")
+            )
+            .arg(
+                Arg::new("json")
+                    .long("json")
+                    .action(ArgAction::SetTrue)
+                    .help("Print the matches as JSON Lines, one object per matching \
+                           line followed by a summary object")
+            )
+            .arg(
+                Arg::new("watch")
+                    .long("watch")
+                    .action(ArgAction::SetTrue)
+                    .help("In server mode, watch the project root and keep the database up to date")
+            )
+            .arg(
+                Arg::new("listen")
+                    .long("listen")
+                    .action(ArgAction::Set)
+                    .value_name("ADDR")
+                    .conflicts_with("connect")
+                    .help("In server mode, the address to listen on: host:port, or unix: \
+                           and the path of a socket. Defaults to $IDFIND_ADDR, the addr of \
+                           the config file or a socket only the user can reach")
+            )
+            .arg(
+                Arg::new("connect")
+                    .long("connect")
+                    .action(ArgAction::Set)
+                    .value_name("ADDR")
+                    .help("In search mode, the address of the server, see --listen")
+            )
+            .arg(
+                Arg::new("persist")
+                    .long("persist")
+                    .action(ArgAction::SetTrue)
+                    .requires("watch")
+                    .help("Save the changes seen while watching back to the database")
+            )
             .get_matches();
 
 
@@ -70,11 +313,11 @@ impl CLIArgs {
             match Path::new(path).canonicalize() {
                 Ok(path) if path.is_dir() => path,
                 Ok(path) => {
-                    println!("Invalid path: {path:?} is not a directory");
+                    eprintln!("Invalid path: {path:?} is not a directory");
                     std::process::exit(-1);
                 }
                 Err(err) => {
-                    println!("Invalid project root: {err}");
+                    eprintln!("Invalid project root: {err}");
                     std::process::exit(-1);
                 }
             }
@@ -84,31 +327,118 @@ impl CLIArgs {
 
         let database = if let Some(path) = matches.get_one::<String>("database") {
             if !Path::new(path).is_file() {
-                println!("Database should be a valid file");
+                eprintln!("Database should be a valid file");
                 std::process::exit(-1);
             }
 
             path.to_string()
 
+        } else if ["cli", "search", "update"].contains(&mode.as_str()) {
+
+            // Look for the default database of the project, or of the project
+            // that the current dir is a part of
+            let database = if project.as_os_str().is_empty() {
+                std::env::current_dir().ok().and_then(|dir| find_default_db(&dir))
+            } else {
+                default_db_path(&project).filter(|path| path.is_file())
+            };
+
+            match database {
+                Some(path) => path.to_string_lossy().to_string(),
+                None       => {
+                    eprintln!("No database found for the project. Index the project \
+                               first or pass the database with --database");
+                    std::process::exit(-1);
+                }
+            }
+
         } else {
             String::new()
         };
 
+        // The output path has to be absolute, as indexing happens from within
+        // the project root
+        let output = matches.get_one::<String>("output").map(|path| {
+            std::env::current_dir().unwrap_or_default().join(path)
+        });
+
         let expr = matches.get_one::<String>("expression")
             .unwrap_or(&empty_string)
             .to_string();
 
+        let case = if matches.get_flag("ignore-case") {
+            Case::Insensitive
+        } else if matches.get_flag("case-sensitive") {
+            Case::Sensitive
+        } else {
+            Case::Smart
+        };
+
+        let format = if matches.get_flag("json") {
+            Format::Json
+        } else if matches.get_flag("vimgrep") {
+            Format::Vimgrep
+        } else if matches.get_flag("files-with-matches") {
+            Format::Files
+        } else if matches.get_flag("count") {
+            Format::Count
+        } else {
+            Format::Text
+        };
+
+        // -A and -B override the amount of context set by -C on their side
+        let context = matches.get_one::<usize>("context").copied().unwrap_or(0);
+        let before  = matches.get_one::<usize>("before-context").copied().unwrap_or(context);
+        let after   = matches.get_one::<usize>("after-context").copied().unwrap_or(context);
+
+        let globs = |id: &str| matches.get_many::<String>(id)
+            .unwrap_or_default()
+            .cloned()
+            .collect::<Vec<_>>();
+
         let include_exts = matches.get_many::<String>("include-ext")
             .unwrap_or_default()
             .map(|x| x.to_string())
             .collect::<Vec<_>>();
 
         CLIArgs {
-            mode: mode,
-            project: project,
-            database: database,
-            expr: expr,
+            mode,
+            project,
+            database,
+            output,
+            expr,
+            matching: MatchOptions {
+                regex: matches.get_flag("regex"),
+                boolean: matches.get_flag("boolean"),
+                case,
+                word: matches.get_flag("word"),
+            },
+            printing: PrintOptions {
+                format,
+                sort: if matches.get_one::<String>("sort").unwrap() == "rank" {
+                    Sort::Rank
+                } else {
+                    Sort::Path
+                },
+                before,
+                after,
+                max_width: matches.get_one::<usize>("max-width").copied().unwrap_or(MAX_WIDTH),
+                null: matches.get_flag("null"),
+                max_count: matches.get_one::<usize>("max-count").copied(),
+                max_results: matches.get_one::<usize>("max-results").copied(),
+                explain: matches.get_flag("explain"),
+            },
+            include: globs("include"),
+            exclude: globs("exclude"),
+            offset: *matches.get_one::<usize>("offset").unwrap(),
+            limit: matches.get_one::<usize>("limit").copied(),
             include_ext: include_exts,
+            no_ignore: matches.get_flag("no-ignore"),
+            watch: matches.get_flag("watch"),
+            persist: matches.get_flag("persist"),
+            addr: server_addr(matches.get_one::<String>("listen")
+                              .or(matches.get_one::<String>("connect"))
+                              .map(String::as_str)),
         }
     }
 }
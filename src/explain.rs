@@ -0,0 +1,53 @@
+//! The report of `--explain` on how a search was run: the steps of the index
+//! lookup with the number of files left after each of them, how many of the
+//! files which were read actually had a match, and where the time went.
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::*;
+
+/// A step of the index lookup, see `Index::lookup`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Step {
+    /// What the step does, like `and` or `trigram "foo"`
+    pub op:    String,
+    /// The number of files matching the step on its own. For a trigram this is
+    /// the size of its posting list.
+    pub files: usize,
+    /// The number of files left after the step was applied to the ones before
+    /// it in an `and`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub left:  Option<usize>,
+    /// The steps this one is made of, in the order they were run
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<Step>,
+}
+
+/// How the files to search were picked
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Explain {
+    pub plan:            Step,
+    /// The files which the index says can contain a match
+    pub candidate_files: usize,
+    /// The candidates left after the path filters
+    pub filtered_files:  usize,
+    pub lookup_ns:       u64,
+    pub filter_ns:       u64,
+    /// The time spent reading the files, which is filled in by whoever reads
+    /// them
+    #[serde(default)]
+    pub verify_ns:       u64,
+}
+
+impl Step {
+
+    /// The step of looking up the posting list of `key`
+    pub fn trigram(key: Trigram, files: usize, left: Option<usize>) -> Step {
+        Step {
+            op:    format!("trigram \"{}\"", key.to_be_bytes()[1..].escape_ascii()),
+            files,
+            left,
+            steps: vec![],
+        }
+    }
+}
@@ -0,0 +1,305 @@
+//! The boolean query language. A query is made of terms combined with `AND`,
+//! `OR` and `NOT`:
+//!
+//! ```text
+//! mutex unsafe             files with both mutex and unsafe
+//! mutex AND unsafe         same as above
+//! lock OR mutex            files with either lock or mutex
+//! alloc NOT test           files with alloc but without test
+//! alloc -test              same as above
+//! (lock OR mutex) -test    parentheses group terms
+//! "fn main"                quotes make a term with spaces or keywords
+//! ```
+//!
+//! `NOT` binds tighter than `AND`, which binds tighter than `OR`.
+
+use crate::query::Query;
+
+/// A parsed boolean query. The terms are referred to by their index in the list
+/// of terms returned by `parse`.
+pub enum Expr {
+    Term(usize),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Not(Box<Expr>),
+}
+
+impl Expr {
+
+    /// Evaluate the expression, with `term` telling if a term is present
+    pub fn eval<F: Fn(usize) -> bool>(&self, term: &F) -> bool {
+        match self {
+            Expr::Term(idx)  => term(*idx),
+            Expr::And(exprs) => exprs.iter().all(|expr| expr.eval(term)),
+            Expr::Or(exprs)  => exprs.iter().any(|expr| expr.eval(term)),
+            Expr::Not(expr)  => !expr.eval(term),
+        }
+    }
+
+    /// Build the index query of the expression from the queries of its terms.
+    /// The index can only tell which files may contain a term, not which ones
+    /// surely don't, so negated terms can't narrow down the files.
+    pub fn query(&self, terms: &[Query]) -> Query {
+        match self {
+            Expr::Term(idx)  => terms[*idx].clone(),
+            Expr::And(exprs) => exprs.iter()
+                .map(|expr| expr.query(terms))
+                .fold(Query::All, Query::and),
+            Expr::Or(exprs)  => exprs.iter()
+                .map(|expr| expr.query(terms))
+                .fold(Query::None, Query::or),
+            Expr::Not(_)     => Query::All,
+        }
+    }
+
+    /// Mark the terms which appear outside of a `NOT` in `positive`
+    pub fn positive(&self, positive: &mut [bool]) {
+        match self {
+            Expr::Term(idx)  => positive[*idx] = true,
+            Expr::And(exprs) |
+            Expr::Or(exprs)  => exprs.iter().for_each(|expr| expr.positive(positive)),
+            Expr::Not(_)     => {}
+        }
+    }
+}
+
+#[derive(PartialEq)]
+enum Token {
+    Open,
+    Close,
+    And,
+    Or,
+    Not,
+    Word(String),
+}
+
+/// Parse the boolean query `input`. Returns the expression along with the list
+/// of its terms.
+pub fn parse(input: &str) -> Result<(Expr, Vec<String>), String> {
+
+    let mut parser = Parser {
+        tokens: lex(input)?,
+        pos:    0,
+        terms:  vec![],
+    };
+
+    let expr = parser.or()?;
+    if parser.pos < parser.tokens.len() {
+        return Err("Unexpected `)`".to_string());
+    }
+
+    Ok((expr, parser.terms))
+}
+
+/// Split the query into tokens
+fn lex(input: &str) -> Result<Vec<Token>, String> {
+
+    let mut tokens = vec![];
+    let mut chars  = input.chars().peekable();
+
+    while let Some(&chr) = chars.peek() {
+        match chr {
+            _ if chr.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            '-' => {
+                chars.next();
+                tokens.push(Token::Not);
+            }
+            '"' => {
+                chars.next();
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"')  => break,
+                        Some('\\') => word.extend(chars.next()),
+                        Some(chr)  => word.push(chr),
+                        None       => return Err("Unterminated quote".to_string()),
+                    }
+                }
+                tokens.push(Token::Word(word));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&chr) = chars.peek() {
+                    if chr.is_whitespace() || chr == '(' || chr == ')' || chr == '"' {
+                        break;
+                    }
+                    word.push(chr);
+                    chars.next();
+                }
+
+                tokens.push(match word.as_str() {
+                    "AND" => Token::And,
+                    "OR"  => Token::Or,
+                    "NOT" => Token::Not,
+                    _     => Token::Word(word),
+                });
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos:    usize,
+    terms:  Vec<String>,
+}
+
+impl Parser {
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    /// or := and ("OR" and)*
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut exprs = vec![self.and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            exprs.push(self.and()?);
+        }
+
+        Ok(if exprs.len() == 1 { exprs.remove(0) } else { Expr::Or(exprs) })
+    }
+
+    /// and := not ("AND"? not)*
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut exprs = vec![self.not()?];
+        loop {
+            match self.peek() {
+                Some(Token::And) => self.pos += 1,
+                Some(Token::Or) | Some(Token::Close) | None => break,
+                _ => {}
+            }
+            exprs.push(self.not()?);
+        }
+
+        Ok(if exprs.len() == 1 { exprs.remove(0) } else { Expr::And(exprs) })
+    }
+
+    /// not := "NOT" not | "(" or ")" | term
+    fn not(&mut self) -> Result<Expr, String> {
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+
+        match token {
+            Some(Token::Not)  => Ok(Expr::Not(Box::new(self.not()?))),
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                if self.peek() != Some(&Token::Close) {
+                    return Err("Missing `)`".to_string());
+                }
+                self.pos += 1;
+                Ok(expr)
+            }
+            Some(Token::Word(word)) => {
+                self.terms.push(word.clone());
+                Ok(Expr::Term(self.terms.len() - 1))
+            }
+            Some(Token::And) | Some(Token::Or) => Err("Missing a term before an operator".to_string()),
+            Some(Token::Close) => Err("Unexpected `)`".to_string()),
+            None => Err("Missing a term at the end of the query".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write the expression as an s-expression, with the terms in place
+    fn show(expr: &Expr, terms: &[String]) -> String {
+        let list = |op: &str, exprs: &[Expr]| {
+            let exprs = exprs.iter().map(|expr| show(expr, terms)).collect::<Vec<_>>();
+            format!("({op} {})", exprs.join(" "))
+        };
+
+        match expr {
+            Expr::Term(idx)  => terms[*idx].clone(),
+            Expr::And(exprs) => list("and", exprs),
+            Expr::Or(exprs)  => list("or", exprs),
+            Expr::Not(expr)  => format!("(not {})", show(expr, terms)),
+        }
+    }
+
+    fn parsed(input: &str) -> String {
+        let (expr, terms) = parse(input).unwrap();
+        show(&expr, &terms)
+    }
+
+    #[test]
+    fn terms() {
+        assert_eq!(parsed("mutex"), "mutex");
+        assert_eq!(parsed("  mutex  "), "mutex");
+        assert_eq!(parsed("\"fn main\""), "fn main");
+        assert_eq!(parsed("\"OR\" \"a \\\"b\\\"\""), "(and OR a \"b\")");
+        // A dash only negates at the start of a term
+        assert_eq!(parsed("foo-bar"), "foo-bar");
+        // Lowercase keywords are plain terms
+        assert_eq!(parsed("a or b"), "(and a or b)");
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(parsed("a b"), "(and a b)");
+        assert_eq!(parsed("a AND b c"), "(and a b c)");
+        assert_eq!(parsed("a OR b c"), "(or a (and b c))");
+        assert_eq!(parsed("a b OR c"), "(or (and a b) c)");
+        assert_eq!(parsed("a OR b OR c"), "(or a b c)");
+        assert_eq!(parsed("NOT a b"), "(and (not a) b)");
+        assert_eq!(parsed("a -b OR c"), "(or (and a (not b)) c)");
+        assert_eq!(parsed("NOT NOT a"), "(not (not a))");
+        assert_eq!(parsed("-(a OR b) c"), "(and (not (or a b)) c)");
+        assert_eq!(parsed("(a OR b) (c OR d)"), "(and (or a b) (or c d))");
+        assert_eq!(parsed("((a))"), "a");
+    }
+
+    #[test]
+    fn errors() {
+        let cases = [
+            ("",            "Missing a term at the end of the query"),
+            ("a OR",        "Missing a term at the end of the query"),
+            ("a -",         "Missing a term at the end of the query"),
+            ("OR a",        "Missing a term before an operator"),
+            ("a AND AND b", "Missing a term before an operator"),
+            ("(a",          "Missing `)`"),
+            ("(a OR b",     "Missing `)`"),
+            ("a)",          "Unexpected `)`"),
+            ("()",          "Unexpected `)`"),
+            ("\"abc",       "Unterminated quote"),
+        ];
+
+        for (input, error) in cases {
+            assert_eq!(parse(input).err().as_deref(), Some(error), "{input:?}");
+        }
+    }
+
+    #[test]
+    fn eval_and_positive_terms() {
+        let (expr, terms) = parse("(a OR b) -c").unwrap();
+        let terms = &terms;
+        let has = |present: &'static [&str]| {
+            move |idx: usize| present.contains(&terms[idx].as_str())
+        };
+
+        assert!(expr.eval(&has(&["a"])));
+        assert!(expr.eval(&has(&["b"])));
+        assert!(!expr.eval(&has(&["a", "c"])));
+        assert!(!expr.eval(&has(&[])));
+
+        let mut positive = vec![false; terms.len()];
+        expr.positive(&mut positive);
+        assert_eq!(positive, [true, true, false]);
+    }
+}
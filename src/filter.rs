@@ -0,0 +1,151 @@
+//! Filters on the paths of the files that are searched, applied to the files
+//! which can contain a match before any of them is read. The globs follow the
+//! rules of the .gitignore files:
+//!
+//! ```text
+//! *.h          files named *.h in any dir
+//! tests/       everything in any dir named tests
+//! src/net      the file src/net, or everything under the dir src/net
+//! src/**/*.rs  files named *.rs anywhere under src
+//! /Makefile    only the Makefile at the root, as does ./Makefile
+//! ```
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+use std::path::Path;
+
+/// Narrows down the files of a search by their path, relative to the project
+/// root
+pub struct PathFilter {
+    /// A file has to match one of these, unless there are none
+    include: Option<GlobSet>,
+    /// A file must not match any of these
+    exclude: GlobSet,
+}
+
+impl PathFilter {
+
+    /// Create the filter for the `include` and `exclude` globs
+    pub fn new(include: &[String], exclude: &[String]) -> Result<PathFilter, String> {
+        let include = match include.is_empty() {
+            true  => None,
+            false => Some(build(include)?),
+        };
+
+        Ok(PathFilter { include, exclude: build(exclude)? })
+    }
+
+    /// Check if the file at `path` should be searched
+    pub fn is_match(&self, path: &Path) -> bool {
+        let path = path.strip_prefix("./").unwrap_or(path);
+
+        self.include.as_ref().is_none_or(|include| include.is_match(path))
+            && !self.exclude.is_match(path)
+    }
+}
+
+/// Build the set of all the `globs`
+fn build(globs: &[String]) -> Result<GlobSet, String> {
+    let mut set = GlobSetBuilder::new();
+
+    for glob in globs {
+        // A glob without a `/`, other than a trailing one, is matched in any
+        // dir unless it starts with `./`, and a glob of a dir matches all the
+        // files under it
+        let pattern = match glob.strip_prefix("./").or_else(|| glob.strip_prefix('/')) {
+            Some(anchored) => anchored.trim_end_matches('/').to_string(),
+            None           => {
+                let pattern = glob.trim_end_matches('/');
+                match pattern.contains('/') {
+                    true  => pattern.to_string(),
+                    false => format!("**/{pattern}"),
+                }
+            }
+        };
+
+        for pattern in [pattern.clone(), format!("{pattern}/**")] {
+            let glob = GlobBuilder::new(&pattern)
+                .literal_separator(true)
+                .build()
+                .map_err(|err| format!("Invalid glob `{glob}`: {}", err.kind()))?;
+            set.add(glob);
+        }
+    }
+
+    set.build().map_err(|err| format!("Invalid glob: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> PathFilter {
+        let strings = |globs: &[&str]| {
+            globs.iter().map(|glob| glob.to_string()).collect::<Vec<_>>()
+        };
+        PathFilter::new(&strings(include), &strings(exclude)).unwrap()
+    }
+
+    /// Check which of `paths` are matched by the single glob `glob`
+    fn check(glob: &str, matches: &[&str], misses: &[&str]) {
+        let filter = filter(&[glob], &[]);
+        for path in matches {
+            assert!(filter.is_match(Path::new(path)), "{glob:?} doesn't match {path:?}");
+        }
+        for path in misses {
+            assert!(!filter.is_match(Path::new(path)), "{glob:?} matches {path:?}");
+        }
+    }
+
+    #[test]
+    fn globs_without_a_slash_match_in_any_dir() {
+        check("*.h", &["a.h", "src/a.h", "src/net/a.h"], &["a.hpp", "a.c", "h"]);
+        check("Makefile", &["Makefile", "lib/Makefile"], &["Makefile.am", "lib/GNUmakefile"]);
+    }
+
+    #[test]
+    fn dirs_match_everything_under_them() {
+        check("tests/", &["tests/a.rs", "src/tests/a.rs", "src/tests/unit/b.rs"],
+              &["tests.rs", "mytests/a.rs", "src/test/a.rs"]);
+        check("src/net", &["src/net", "src/net/a.c", "src/net/tcp/b.c"],
+              &["src/network.c", "lib/src/net/a.c", "net/a.c"]);
+    }
+
+    #[test]
+    fn globs_with_a_slash_are_anchored() {
+        check("src/**/*.rs", &["src/a.rs", "src/x/y/a.rs"],
+              &["a.rs", "lib/a.rs", "lib/src/a.rs"]);
+        check("src/*.rs", &["src/a.rs"], &["src/x/a.rs"]);
+        check("/Makefile", &["Makefile"], &["lib/Makefile"]);
+        check("/build/", &["build/out.o"], &["src/build/out.o"]);
+    }
+
+    #[test]
+    fn dot_slash_prefixes() {
+        check("./src/net", &["src/net/a.c", "./src/net/a.c"], &["lib/src/net/a.c"]);
+        check("*.h", &["./a.h", "./src/a.h"], &["./a.c"]);
+        check("./tests/", &["tests/a.rs", "./tests/a.rs"], &["src/tests/a.rs"]);
+    }
+
+    #[test]
+    fn include_and_exclude() {
+        let all = filter(&[], &[]);
+        assert!(all.is_match(Path::new("anything/at/all.txt")));
+
+        let filter = filter(&["*.rs", "*.toml"], &["tests/", "src/gen"]);
+        assert!(filter.is_match(Path::new("src/main.rs")));
+        assert!(filter.is_match(Path::new("Cargo.toml")));
+        assert!(!filter.is_match(Path::new("Readme.md")));
+        assert!(!filter.is_match(Path::new("tests/cli.rs")));
+        assert!(!filter.is_match(Path::new("src/gen/table.rs")));
+        assert!(filter.is_match(Path::new("src/generate.rs")));
+    }
+
+    #[test]
+    fn invalid_globs() {
+        let err = PathFilter::new(&["a[".to_string()], &[]).err().unwrap();
+        assert!(err.starts_with("Invalid glob `a[`"), "{err}");
+
+        assert!(PathFilter::new(&[], &["{a".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,595 @@
+//! The on-disk layout of the search database. All integers are little endian.
+//!
+//! ```text
+//! +-------------------+  0
+//! | header            |  HEADER_SIZE bytes, see `Header`
+//! +-------------------+
+//! | project root      |  `root_len` bytes
+//! +-------------------+
+//! | metadata          |  `meta_len` bytes of JSON, see `Metadata`
+//! +-------------------+  files_off
+//! | file table        |  `file_count` x FILE_ENTRY_SIZE, sorted by id
+//! +-------------------+  keys_off
+//! | trigram dict      |  `key_count` x KEY_ENTRY_SIZE, sorted by key
+//! +-------------------+  postings_off
+//! | posting lists     |  compressed posting lists, see `PostingList`
+//! +-------------------+  names_off
+//! | file names        |  raw path bytes referenced by the file table
+//! +-------------------+
+//! ```
+//!
+//! File table entry: `id: u32`, `name_len: u32`, `name_off: u64` (relative to
+//! `names_off`), `mtime: u64` (nanoseconds since the epoch), `size: u64`,
+//! `hash: u64` (see `FileMeta`).
+//!
+//! Trigram dict entry: `key: u32` (a packed `Trigram`), `count: u32` (number of
+//! ids in the posting list), `size: u32` (size in bytes of the posting list),
+//! `off: u64` (offset of the posting list relative to `postings_off`).
+//!
+//! Version 4 databases only differ in the metadata section, which holds just the
+//! `IndexOptions`. They can still be read and are saved in the current format
+//! the next time they are written.
+
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+use std::path::Path;
+use std::io::{self, ErrorKind, Read, Result, Write};
+
+use crate::idb::{FileMeta, IndexOptions};
+use crate::utils::{Trigram, NGRAM};
+
+/// Magic bytes at the start of every database file
+pub const MAGIC: [u8; 8] = *b"IDFINDDB";
+
+/// Current version of the on-disk format
+pub const VERSION: u32 = 5;
+
+/// Oldest version of the on-disk format that can still be read
+pub const MIN_VERSION: u32 = 4;
+
+pub const HEADER_SIZE:     usize = 80;
+pub const FILE_ENTRY_SIZE: usize = 40;
+pub const KEY_ENTRY_SIZE:  usize = 20;
+
+/// The fixed size header at the start of the database file
+pub struct Header {
+    pub version:      u32,
+    pub cur_id:       u32,
+    pub file_count:   u64,
+    pub key_count:    u64,
+    pub root_len:     u64,
+    pub meta_len:     u64,
+    pub files_off:    u64,
+    pub keys_off:     u64,
+    pub postings_off: u64,
+    pub names_off:    u64,
+}
+
+impl Header {
+
+    /// Compute the header for a database with the given number of entries in
+    /// each section. The sections are laid out one after the other.
+    pub fn new(cur_id: u32, root_len: usize, meta_len: usize, file_count: usize,
+               key_count: usize, postings_size: usize) -> Header {
+
+        let files_off    = (HEADER_SIZE + root_len + meta_len) as u64;
+        let keys_off     = files_off + (file_count * FILE_ENTRY_SIZE) as u64;
+        let postings_off = keys_off + (key_count * KEY_ENTRY_SIZE) as u64;
+        let names_off    = postings_off + postings_size as u64;
+
+        Header {
+            version: VERSION,
+            cur_id,
+            file_count: file_count as u64,
+            key_count: key_count as u64,
+            root_len: root_len as u64,
+            meta_len: meta_len as u64,
+            files_off,
+            keys_off,
+            postings_off,
+            names_off,
+        }
+    }
+
+    /// Parse and validate the header at the start of `buf`
+    pub fn parse(buf: &[u8]) -> Result<Header> {
+
+        if buf.len() < HEADER_SIZE || buf[..8] != MAGIC {
+            return Err(invalid("Not an idfind database (JSON databases can be \
+                                converted with `--mode convert`)"));
+        }
+
+        let version = read_u32(buf, 8);
+        if version > VERSION {
+            return Err(invalid(format!("Database format version {version} is newer \
+                                        than the supported version {VERSION}, upgrade \
+                                        idfind or reindex the project")));
+        }
+
+        if version < MIN_VERSION {
+            return Err(invalid(format!("Database format version {version} is no \
+                                        longer supported, reindex the project with \
+                                        `--mode index`")));
+        }
+
+        let header = Header {
+            version,
+            cur_id:       read_u32(buf, 12),
+            file_count:   read_u64(buf, 16),
+            key_count:    read_u64(buf, 24),
+            root_len:     read_u64(buf, 32),
+            meta_len:     read_u64(buf, 40),
+            files_off:    read_u64(buf, 48),
+            keys_off:     read_u64(buf, 56),
+            postings_off: read_u64(buf, 64),
+            names_off:    read_u64(buf, 72),
+        };
+
+        // Make sure that all the sections are in bounds so that the readers do
+        // not have to worry about it
+        let files_end = header.files_off
+            .checked_add(header.file_count.saturating_mul(FILE_ENTRY_SIZE as u64));
+        let keys_end = header.keys_off
+            .checked_add(header.key_count.saturating_mul(KEY_ENTRY_SIZE as u64));
+
+        let in_order = (HEADER_SIZE as u64).checked_add(header.root_len)
+            .and_then(|off| off.checked_add(header.meta_len))
+            == Some(header.files_off)
+            && files_end.is_some_and(|end| end <= header.keys_off)
+            && keys_end.is_some_and(|end| end <= header.postings_off)
+            && header.postings_off <= header.names_off
+            && header.names_off <= buf.len() as u64;
+
+        if !in_order {
+            return Err(invalid("Corrupt database header"));
+        }
+
+        Ok(header)
+    }
+
+    /// Serialize the header into `out`
+    pub fn write<W: Write>(&self, out: &mut W) -> Result<()> {
+        out.write_all(&MAGIC)?;
+        out.write_all(&self.version.to_le_bytes())?;
+        out.write_all(&self.cur_id.to_le_bytes())?;
+        out.write_all(&self.file_count.to_le_bytes())?;
+        out.write_all(&self.key_count.to_le_bytes())?;
+        out.write_all(&self.root_len.to_le_bytes())?;
+        out.write_all(&self.meta_len.to_le_bytes())?;
+        out.write_all(&self.files_off.to_le_bytes())?;
+        out.write_all(&self.keys_off.to_le_bytes())?;
+        out.write_all(&self.postings_off.to_le_bytes())?;
+        out.write_all(&self.names_off.to_le_bytes())?;
+        Ok(())
+    }
+}
+
+/// Check if the file at `path` is a binary idfind database, of any version
+pub fn is_database(path: &Path) -> bool {
+    let mut magic = [0u8; 8];
+    fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok_and(|_| magic == MAGIC)
+}
+
+/// The metadata section of the database, describing how it was built
+#[derive(Serialize, Deserialize)]
+pub struct Metadata {
+    /// Version of idfind that wrote the database
+    pub writer:  String,
+    pub options: IndexOptions,
+}
+
+impl Metadata {
+
+    /// The metadata of a database written by this version of idfind
+    pub fn new(options: IndexOptions) -> Metadata {
+        Metadata {
+            writer: env!("CARGO_PKG_VERSION").to_string(),
+            options,
+        }
+    }
+
+    /// Parse the metadata section of a database of format `version`, and make
+    /// sure that the database can be searched by this version of idfind
+    pub fn parse(buf: &[u8], version: u32) -> Result<Metadata> {
+
+        let meta = if version < 5 {
+            serde_json::from_slice(buf).map(|options| Metadata {
+                writer: "unknown".to_string(),
+                options,
+            })
+        } else {
+            serde_json::from_slice(buf)
+        };
+
+        let meta = meta.map_err(|err| invalid(format!("Corrupt database metadata: {err}")))?;
+
+        if meta.options.ngram != NGRAM {
+            return Err(invalid(format!("Database was indexed with {}-grams by idfind \
+                                        {} but this version uses {NGRAM}-grams, \
+                                        reindex the project with `--mode index`",
+                                       meta.options.ngram, meta.writer)));
+        }
+
+        Ok(meta)
+    }
+}
+
+/// An entry of the file table
+pub struct FileEntry {
+    pub id:       u32,
+    pub name_len: u32,
+    pub name_off: u64,
+    pub meta:     FileMeta,
+}
+
+impl FileEntry {
+    pub fn parse(buf: &[u8]) -> FileEntry {
+        FileEntry {
+            id:       read_u32(buf, 0),
+            name_len: read_u32(buf, 4),
+            name_off: read_u64(buf, 8),
+            meta:     FileMeta {
+                mtime: read_u64(buf, 16),
+                size:  read_u64(buf, 24),
+                hash:  read_u64(buf, 32),
+            },
+        }
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W) -> Result<()> {
+        out.write_all(&self.id.to_le_bytes())?;
+        out.write_all(&self.name_len.to_le_bytes())?;
+        out.write_all(&self.name_off.to_le_bytes())?;
+        out.write_all(&self.meta.mtime.to_le_bytes())?;
+        out.write_all(&self.meta.size.to_le_bytes())?;
+        out.write_all(&self.meta.hash.to_le_bytes())?;
+        Ok(())
+    }
+}
+
+/// An entry of the trigram dictionary
+pub struct KeyEntry {
+    pub key:   Trigram,
+    pub count: u32,
+    pub size:  u32,
+    pub off:   u64,
+}
+
+impl KeyEntry {
+    pub fn parse(buf: &[u8]) -> KeyEntry {
+        KeyEntry {
+            key:   read_u32(buf, 0),
+            count: read_u32(buf, 4),
+            size:  read_u32(buf, 8),
+            off:   read_u64(buf, 12),
+        }
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W) -> Result<()> {
+        out.write_all(&self.key.to_le_bytes())?;
+        out.write_all(&self.count.to_le_bytes())?;
+        out.write_all(&self.size.to_le_bytes())?;
+        out.write_all(&self.off.to_le_bytes())?;
+        Ok(())
+    }
+}
+
+pub fn read_u32(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(buf[off..off + 4].try_into().unwrap())
+}
+
+pub fn read_u64(buf: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(buf[off..off + 8].try_into().unwrap())
+}
+
+/// Get the sub slice `buf[off..off+len]`, failing if it is out of bounds
+pub fn slice(buf: &[u8], off: u64, len: u64) -> Result<&[u8]> {
+    let start = usize::try_from(off).map_err(|_| invalid("Corrupt database"))?;
+    let end   = usize::try_from(len).ok()
+        .and_then(|len| start.checked_add(len))
+        .filter(|&end| end <= buf.len())
+        .ok_or_else(|| invalid("Corrupt database"))?;
+
+    Ok(&buf[start..end])
+}
+
+pub fn invalid<T: Into<String>>(message: T) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use memmap2::MmapMut;
+
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::path::PathBuf;
+
+    use crate::idb::Idb;
+    use crate::index::Index;
+    use crate::mapped::MappedIdb;
+    use crate::postings::PostingList;
+    use crate::utils::{tokenize, trigram};
+
+    /// A small database, with a posting list spanning several blocks
+    fn sample() -> Idb {
+        let mut db = Idb::new(Path::new("/src/project"), IndexOptions::default());
+
+        let mut files = vec![
+            (3, "src/main.rs".to_string(), "fn main() {}".to_string()),
+            (7, "src/lib.rs".to_string(),  "pub fn lib() {}".to_string()),
+        ];
+        files.extend((10..150).map(|id| (id, format!("gen/{id}.rs"), format!("shared {id}"))));
+        files.push((150, "short".to_string(), "ab".to_string()));
+
+        let mut keys: BTreeMap<Trigram, BTreeSet<u32>> = BTreeMap::new();
+        for (id, name, text) in files {
+            db.idx_db.insert(id, PathBuf::from(name));
+            db.meta_db.insert(id, FileMeta {
+                mtime: id as u64 * 1000,
+                size:  text.len() as u64,
+                hash:  id as u64 + 99,
+            });
+            for key in tokenize(&text) {
+                keys.entry(key).or_default().insert(id);
+            }
+        }
+
+        db.str_db = keys.into_iter()
+            .map(|(key, ids)| (key, PostingList::from_sorted(ids)))
+            .collect();
+        db.cur_id = 200;
+        db
+    }
+
+    fn to_bytes(db: &Idb) -> Vec<u8> {
+        let mut out = vec![];
+        db.write(&mut out).unwrap();
+        out
+    }
+
+    /// Open the database in `bytes` with the mapped reader
+    fn open(bytes: &[u8]) -> Result<MappedIdb> {
+        let mut map = MmapMut::map_anon(bytes.len())?;
+        map.copy_from_slice(bytes);
+        MappedIdb::from_map(map.make_read_only()?)
+    }
+
+    fn set_u64(bytes: &mut [u8], off: usize, value: u64) {
+        bytes[off..off + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn assert_invalid(result: Result<MappedIdb>) {
+        match result {
+            Ok(_)    => panic!("a corrupt database was accepted"),
+            Err(err) => assert_eq!(err.kind(), ErrorKind::InvalidData, "{err}"),
+        }
+    }
+
+    /// Read everything that can be read from `db`, none of which may panic
+    fn read_all(db: &MappedIdb) {
+        db.files().for_each(drop);
+        db.keys().for_each(|(_, list)| { list.decode(); });
+        db.file_ids().into_iter().for_each(|id| { db.file_name(id); });
+    }
+
+    #[test]
+    fn round_trip() {
+        let db     = sample();
+        let mapped = open(&to_bytes(&db)).unwrap();
+
+        assert_eq!(mapped.project_root(), Path::new("/src/project"));
+        assert_eq!(mapped.cur_id(), 200);
+        assert_eq!(mapped.meta.writer, env!("CARGO_PKG_VERSION"));
+        assert_eq!(mapped.meta.options.ngram, NGRAM);
+
+        let mut ids = db.idx_db.keys().copied().collect::<Vec<_>>();
+        ids.sort_unstable();
+        assert_eq!(mapped.file_ids(), ids);
+
+        let files = mapped.files().collect::<Vec<_>>();
+        assert_eq!(files.len(), ids.len());
+        for ((id, name, meta), expected) in files.iter().zip(&ids) {
+            assert_eq!(id, expected);
+            assert_eq!(name, &db.idx_db[id]);
+            assert_eq!(mapped.file_name(*id).as_ref(), Some(name));
+
+            let expected = db.meta_db[id];
+            assert_eq!((meta.mtime, meta.size, meta.hash),
+                       (expected.mtime, expected.size, expected.hash));
+        }
+        assert_eq!(mapped.file_name(5), None);
+        assert_eq!(mapped.file_name(1000), None);
+
+        let keys = mapped.keys().collect::<Vec<_>>();
+        assert_eq!(keys.len(), db.str_db.len());
+        assert!(keys.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        for (key, list) in keys {
+            let expected = db.str_db[&key].as_postings().decode();
+            assert_eq!(list.decode(), expected);
+            assert_eq!(mapped.postings(key).map(|list| list.decode()), Some(expected));
+        }
+
+        assert_eq!(mapped.short_files(), [150]);
+
+        let shared = mapped.postings(trigram(b"sha")).unwrap();
+        assert_eq!(shared.decode(), (10..150).collect::<Vec<_>>());
+        assert!(mapped.postings(trigram(b"zzz")).is_none());
+    }
+
+    #[test]
+    fn empty_database() {
+        let db     = Idb::new(Path::new("/empty"), IndexOptions::default());
+        let mapped = open(&to_bytes(&db)).unwrap();
+
+        assert_eq!(mapped.files().count(), 0);
+        assert_eq!(mapped.keys().count(), 0);
+        assert!(mapped.file_ids().is_empty());
+        assert!(mapped.postings(trigram(b"abc")).is_none());
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes     = to_bytes(&sample());
+        let names_off = read_u64(&bytes, 72) as usize;
+
+        assert!(Header::parse(&[]).is_err());
+
+        // Everything before the names is checked when opening the database. A
+        // cut in the names only loses the names which are cut.
+        for len in 1..bytes.len() {
+            match open(&bytes[..len]) {
+                Ok(db)   => {
+                    assert!(len >= names_off, "accepted a database cut at {len}");
+                    read_all(&db);
+                }
+                Err(err) => assert_eq!(err.kind(), ErrorKind::InvalidData, "{err}"),
+            }
+        }
+    }
+
+    #[test]
+    fn corrupt_header() {
+        let bytes = to_bytes(&sample());
+        let field = |off: usize| read_u64(&bytes, off);
+
+        let cases: [(usize, u64); 11] = [
+            (16, u64::MAX),           // file_count overflows
+            (24, u64::MAX),           // key_count overflows
+            (32, u64::MAX),           // root_len overflows
+            (32, field(32) + 1),      // root runs into the metadata
+            (48, field(48) + 1),      // files_off after the metadata
+            (48, field(48) - 1),      // files_off inside the metadata
+            (56, field(48)),          // keys_off inside the file table
+            (64, field(56)),          // postings_off inside the dict
+            (72, field(64) - 1),      // names_off before postings_off
+            (72, bytes.len() as u64 + 1),
+            (16, field(16) + 1),      // one more file than there is room for
+        ];
+
+        for (off, value) in cases {
+            let mut bytes = bytes.clone();
+            set_u64(&mut bytes, off, value);
+            assert_invalid(open(&bytes));
+        }
+
+        let mut bytes = bytes.clone();
+        bytes[0] = b'X';
+        assert_invalid(open(&bytes));
+    }
+
+    #[test]
+    fn corrupt_entries() {
+        let mut bytes = to_bytes(&sample());
+        let files_off = read_u64(&bytes, 48) as usize;
+        let keys_off  = read_u64(&bytes, 56) as usize;
+
+        // The name of the first file and the posting list of the first key
+        // point out of their sections
+        set_u64(&mut bytes, files_off + 8, u64::MAX - 4);
+        let size = keys_off + 8;
+        bytes[size..size + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        let db  = open(&bytes).unwrap();
+        let key = read_u32(&bytes, keys_off);
+        let id  = read_u32(&bytes, files_off);
+
+        assert!(db.file_name(id).is_none());
+        assert!(db.postings(key).is_none());
+        assert_eq!(db.files().count(), db.file_ids().len() - 1);
+        assert_eq!(db.keys().count(), read_u64(&bytes, 24) as usize - 1);
+        read_all(&db);
+    }
+
+    /// Rewrite the database in `bytes` as format `version` with the metadata
+    /// section `meta`, moving the sections after it
+    fn with_metadata(bytes: &[u8], version: u32, meta: &str) -> Vec<u8> {
+        let start = HEADER_SIZE + read_u64(bytes, 32) as usize;
+        let end   = start + read_u64(bytes, 40) as usize;
+
+        let mut out = [&bytes[..start], meta.as_bytes(), &bytes[end..]].concat();
+        out[8..12].copy_from_slice(&version.to_le_bytes());
+        set_u64(&mut out, 40, meta.len() as u64);
+
+        // files_off, keys_off, postings_off and names_off
+        for off in [48, 56, 64, 72] {
+            let moved = read_u64(bytes, off) as usize - end + start + meta.len();
+            set_u64(&mut out, off, moved as u64);
+        }
+
+        out
+    }
+
+    fn open_err(bytes: &[u8]) -> String {
+        match open(bytes) {
+            Ok(_)    => panic!("an unreadable database was accepted"),
+            Err(err) => {
+                assert_eq!(err.kind(), ErrorKind::InvalidData, "{err}");
+                err.to_string()
+            }
+        }
+    }
+
+    #[test]
+    fn versions() {
+        let mut bytes = to_bytes(&sample());
+        assert_eq!(read_u32(&bytes, 8), VERSION);
+
+        bytes[8..12].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(open_err(&bytes).contains("newer than the supported version"));
+
+        bytes[8..12].copy_from_slice(&(MIN_VERSION - 1).to_le_bytes());
+        assert!(open_err(&bytes).contains("reindex the project"));
+
+        bytes[8..12].copy_from_slice(&0u32.to_le_bytes());
+        assert!(open_err(&bytes).contains("reindex the project"));
+    }
+
+    #[test]
+    fn version_4_metadata() {
+        let db    = sample();
+        let bytes = with_metadata(&to_bytes(&db), 4, r#"{"include_ext":["rs","md"]}"#);
+        let v4    = open(&bytes).unwrap();
+
+        assert_eq!(v4.meta.writer, "unknown");
+        assert_eq!(v4.meta.options.include_ext, ["rs", "md"]);
+        assert!(!v4.meta.options.no_ignore);
+        assert_eq!(v4.meta.options.ngram, NGRAM);
+
+        // The rest of the database is read as before
+        assert_eq!(v4.files().count(), db.idx_db.len());
+        assert_eq!(v4.keys().count(), db.str_db.len());
+        assert_eq!(v4.postings(trigram(b"sha")).map(|list| list.len()), Some(140));
+
+        // The whole metadata of the current version is not valid in version 4
+        let meta = serde_json::to_string(&Metadata::new(IndexOptions::default())).unwrap();
+        assert_invalid(open(&with_metadata(&bytes, 4, &meta)));
+    }
+
+    #[test]
+    fn ngram_mismatch() {
+        let bytes = to_bytes(&sample());
+
+        let meta = r#"{"writer":"9.9.9","options":{"include_ext":[],"ngram":4}}"#;
+        let err  = open_err(&with_metadata(&bytes, VERSION, meta));
+        assert!(err.contains("indexed with 4-grams by idfind 9.9.9"), "{err}");
+        assert!(err.contains("reindex the project"), "{err}");
+
+        let err = open_err(&with_metadata(&bytes, 4, r#"{"include_ext":[],"ngram":2}"#));
+        assert!(err.contains("indexed with 2-grams by idfind unknown"), "{err}");
+
+        let meta = r#"{"writer":"0.1.0","options":{"include_ext":[],"ngram":3}}"#;
+        assert_eq!(open(&with_metadata(&bytes, VERSION, meta)).unwrap().meta.writer, "0.1.0");
+    }
+
+    #[test]
+    fn corrupt_metadata() {
+        let bytes = to_bytes(&sample());
+        assert_invalid(open(&with_metadata(&bytes, VERSION, "{\"writer\":")));
+        assert_invalid(open(&with_metadata(&bytes, VERSION, "[]")));
+    }
+}
@@ -1,110 +1,325 @@
-use rayon;
 use rayon::prelude::*;
 
-use walkdir::{DirEntry, WalkDir};
+use ignore::WalkBuilder;
 
 use serde::{Deserialize, Serialize};
-use serde_json;
 
 use std::fs;
 use std::io::*;
-use std::time::Instant;
-use std::path::PathBuf;
+use std::time::{Instant, UNIX_EPOCH};
+use std::path::{Path, PathBuf};
+use std::os::unix::ffi::OsStrExt;
 use std::sync::{mpsc, Arc};
 use std::collections::{HashMap, HashSet};
 use std::sync::atomic::{AtomicU64, AtomicBool, Ordering};
 
 use crate::utils::*;
+use crate::format::*;
+use crate::index::Index;
+use crate::mapped::MappedIdb;
+use crate::postings::{PostingList, Postings};
 
 /// The search index database
-#[derive(Serialize, Deserialize)]
 pub struct Idb {
     pub cur_id:         u32,
     pub project_root:   PathBuf,
+    pub options:        IndexOptions,
     pub idx_db:         HashMap<u32, PathBuf>,
-    pub str_db:         HashMap<String, HashSet<u32>>,
+    pub meta_db:        HashMap<u32, FileMeta>,
+    pub str_db:         HashMap<Trigram, PostingList>,
+
+    /// Number of files removed since the posting lists were last compacted
+    stale:              usize,
+}
+
+/// The options that were used to build the database. These are saved in the
+/// database so that updating it picks up the same set of files.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IndexOptions {
+    pub include_ext:    Vec<String>,
+
+    /// Index the files ignored by the .gitignore and .ignore files too
+    #[serde(default)]
+    pub no_ignore:      bool,
+
+    /// Number of bytes in the n-grams that were indexed
+    #[serde(default = "default_ngram")]
+    pub ngram:          usize,
+}
+
+impl Default for IndexOptions {
+    fn default() -> IndexOptions {
+        IndexOptions {
+            include_ext: vec![],
+            no_ignore:   false,
+            ngram:       NGRAM,
+        }
+    }
+}
+
+fn default_ngram() -> usize {
+    NGRAM
+}
+
+/// The metadata of an indexed file, used to find the files that changed since
+/// they were indexed
+#[derive(Clone, Copy, Default)]
+pub struct FileMeta {
+    /// Modification time in nanoseconds since the epoch
+    pub mtime:  u64,
+    pub size:   u64,
+    /// `hash` of the contents of the file
+    pub hash:   u64,
+}
+
+impl FileMeta {
+
+    /// Get the metadata of the file at `path`, except for the hash
+    pub fn stat(path: &Path) -> Result<FileMeta> {
+        let meta  = fs::metadata(path)?;
+        let mtime = meta.modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_nanos() as u64)
+            .unwrap_or_default();
+
+        Ok(FileMeta { mtime, size: meta.len(), hash: 0 })
+    }
+}
+
+/// The layout of the databases saved by the older versions of idfind, which
+/// used `String` trigrams and were serialized as JSON
+#[derive(Deserialize)]
+struct JsonIdb {
+    cur_id:         u32,
+    project_root:   PathBuf,
+    idx_db:         HashMap<u32, PathBuf>,
+    str_db:         HashMap<String, HashSet<u32>>,
 }
 
 impl Idb {
 
     /// Create a new Idb for the project at path `project`
-    pub fn new(project: &PathBuf) -> Idb {
+    pub fn new(project: &Path, options: IndexOptions) -> Idb {
         Idb {
             cur_id: 0,
-            project_root: project.clone(),
+            project_root: project.to_path_buf(),
+            options,
             idx_db: HashMap::new(),
+            meta_db: HashMap::new(),
             str_db: HashMap::new(),
+            stale: 0,
         }
     }
 
-    /// Load the search database from file and create an `Idb` instance
+    /// Load the whole search database from file into memory. This is only
+    /// required to modify the database, queries can use a `MappedIdb`.
     pub fn load(path: &String) -> Result<Idb> {
 
-        println!("Loading database: {path}");
+        let mapped = MappedIdb::open(path)?;
+
+        let now = Instant::now();
+
+        let mut db = Idb::new(mapped.project_root(), mapped.meta.options.clone());
+        db.cur_id  = mapped.cur_id();
+
+        for (id, name, meta) in mapped.files() {
+            db.idx_db.insert(id, name);
+            db.meta_db.insert(id, meta);
+        }
+
+        for (key, list) in mapped.keys() {
+            db.str_db.insert(key, PostingList::from_postings(list));
+        }
+
+        print_time_stats("Reading", now.elapsed());
+
+        Ok(db)
+    }
+
+    /// Load a database saved in the old JSON format
+    pub fn load_json(path: &String) -> Result<Idb> {
+
+        println!("Loading JSON database: {path}");
 
         let now = Instant::now();
 
         let json = fs::read_to_string(path)?;
-        let db   = serde_json::from_str(&json)?;
+        let old: JsonIdb = serde_json::from_str(&json)?;
+
+        let mut db = Idb::new(&old.project_root, IndexOptions::default());
+        db.cur_id  = old.cur_id;
+        db.idx_db  = old.idx_db;
+
+        // The old trigrams were made of 3 chars. Every byte level trigram of the
+        // files is contained in one of them, so the file ids of a char trigram
+        // are added to all the byte trigrams in it.
+        let mut str_db: HashMap<Trigram, HashSet<u32>> = HashMap::new();
+        for (key, ids) in old.str_db {
+            for token in tokenize(&key) {
+                str_db.entry(token).or_default().extend(ids.iter().copied());
+            }
+        }
+
+        for (key, ids) in str_db {
+            let mut ids = ids.into_iter().collect::<Vec<_>>();
+            ids.sort_unstable();
+            db.str_db.insert(key, PostingList::from_sorted(ids));
+        }
 
         print_time_stats("Loading", now.elapsed());
 
         Ok(db)
     }
 
-    /// JSON serialize this database and save it into the `sdb.json` file in the
-    /// current working dir
-    pub fn save(&self) -> Result<()> {
-        let json = serde_json::to_string(&self)?;
-        fs::write("sdb.json", json)?;
+    /// Serialize this database in the binary format and save it at `path`. The
+    /// database is written to a temporary file which then replaces `path`, so
+    /// readers either see the old database or the new one and never a partially
+    /// written one. Concurrent writers of the same database are serialized with
+    /// an advisory lock.
+    pub fn save(&self, path: &Path) -> Result<()> {
+
+        let _lock = lock_db(path)?;
+
+        write_atomic(path, |out| self.write(out))
+    }
+
+    /// Serialize this database in the binary format into `out`
+    pub fn write<W: Write>(&self, out: &mut W) -> Result<()> {
+
+        // Sort everything so that the dictionary can be binary searched and the
+        // posting arrays can be merged without any extra work
+        let mut ids = self.idx_db.keys().copied().collect::<Vec<_>>();
+        ids.sort_unstable();
+
+        let mut keys = self.str_db.keys().collect::<Vec<_>>();
+        keys.sort_unstable();
+
+        let project_root = self.project_root.as_os_str().as_bytes();
+        let meta = serde_json::to_vec(&Metadata::new(self.options.clone()))?;
+        let postings_size = self.str_db.values()
+            .map(|list| list.as_postings().size())
+            .sum();
+
+        let header = Header::new(self.cur_id, project_root.len(), meta.len(),
+                                 ids.len(), keys.len(), postings_size);
+
+        header.write(out)?;
+        out.write_all(project_root)?;
+        out.write_all(&meta)?;
+
+        let mut name_off = 0u64;
+        for id in ids.iter() {
+            let name_len = self.idx_db[id].as_os_str().len() as u32;
+            let meta     = self.meta_db.get(id).copied().unwrap_or_default();
+            FileEntry { id: *id, name_len, name_off, meta }.write(out)?;
+            name_off += name_len as u64;
+        }
+
+        let mut off = 0u64;
+        for key in keys.iter() {
+            let list  = self.str_db[*key].as_postings();
+            let entry = KeyEntry {
+                key: **key,
+                count: list.len() as u32,
+                size: list.size() as u32,
+                off,
+            };
+            entry.write(out)?;
+
+            off += entry.size as u64;
+        }
+
+        for key in keys.iter() {
+            self.str_db[*key].as_postings().write(out)?;
+        }
+
+        for id in ids.iter() {
+            out.write_all(self.idx_db[id].as_os_str().as_bytes())?;
+        }
+
         Ok(())
     }
 
     /// Iterate over all keys passed and add them to the database
-    fn update_db(&mut self, fname: &PathBuf, keys: HashSet<String>) {
+    fn update_db(&mut self, fname: &Path, keys: Vec<Trigram>, meta: FileMeta) {
 
         let id = self.cur_id;
         self.cur_id+=1;
-        self.idx_db.insert(id, fname.clone());
-
-        keys.iter().for_each(|key| {
-            if let Some(ref mut set) = self.str_db.get_mut(key) {
-                set.insert(id);
-            } else {
-                let mut set = HashSet::new();
-                set.insert(id);
-                self.str_db.insert(key.to_string(), set);
-            }
+        self.idx_db.insert(id, fname.to_path_buf());
+        self.meta_db.insert(id, meta);
+
+        keys.into_iter().for_each(|key| {
+            self.str_db.entry(key).or_default().push(id);
         });
     }
 
-    /// This function will iterate over the directory that is passed to it and build
-    /// the search database from the files present in those dirs
-    pub fn iterate_dir(&mut self, valid_exts: &Vec<String>) {
+    /// Remove the file `id` from the database. The id is left behind in the
+    /// posting lists until the next `compact`, but it is never returned by
+    /// queries as it can't be mapped back to a file name.
+    fn remove_file(&mut self, id: u32) {
+        if self.idx_db.remove(&id).is_some() {
+            self.stale += 1;
+        }
+        self.meta_db.remove(&id);
+    }
 
-        let ext_filter = !valid_exts.is_empty();
+    /// Drop the ids of the removed files from all the posting lists
+    pub fn compact(&mut self) {
+        if self.stale == 0 {
+            return;
+        }
+        self.stale = 0;
 
-        let is_hidden = |entry: &DirEntry|  {
-            entry.file_name()
-                .to_str()
-                .map(|s| s.starts_with(".") && (s.len() > 1))
-                .unwrap_or(false)
-        };
+        let idx_db = &self.idx_db;
+        self.str_db.par_iter_mut().for_each(|(_, list)| {
+            list.retain(|id| idx_db.contains_key(&id));
+        });
+        self.str_db.retain(|_, list| !list.is_empty());
+    }
+
+    /// Collect all the files under the project root that should be indexed.
+    /// If `scope` is not empty, only the files under the paths in it (relative
+    /// to the project root) are collected. The progress is only printed if
+    /// `verbose` is set.
+    fn enumerate_files(&self, scope: Vec<PathBuf>, verbose: bool) -> Vec<PathBuf> {
+
+        let valid_exts = &self.options.include_ext;
+        let ext_filter = !valid_exts.is_empty();
 
-        println!("Enumerating files...");
+        if verbose {
+            println!("Enumerating files...");
+        }
 
         let now = Instant::now();
 
+        // Dotfiles are always skipped. Unless asked not to, the files ignored by
+        // the .gitignore, .ignore and git exclude files are skipped as well. The
+        // walk starts from the absolute project root rather than the current
+        // dir, which the server shares between the databases it serves.
+        let root = self.project_root.clone();
+        let mut walker = WalkBuilder::new(&root);
+        walker.standard_filters(!self.options.no_ignore)
+              .hidden(true);
+
+        // The walk always starts at the project root, even when only a part of
+        // it is needed, so that the ignore files of all the parent dirs apply.
+        // Only the dirs on the way to the paths in the scope are descended into.
+        if !scope.is_empty() {
+            let root = root.clone();
+            walker.filter_entry(move |entry| {
+                let path = strip_root(&root, entry.path());
+                scope.iter().any(|root| root.starts_with(path) || path.starts_with(root))
+            });
+        }
+
         // Iterate over the dir structure, and collect all the files that we are
         // interested in
-        let files: Vec<PathBuf> = WalkDir::new(".")
-            .into_iter()
-            .filter_entry(|entry| !is_hidden(entry))
+        let files: Vec<PathBuf> = walker.build()
             .filter_map(|x| x.ok())
             .filter(|x| {
 
                 // If this is not a file, then skip this entry
-                if !x.file_type().is_file() {
+                if !x.file_type().is_some_and(|kind| kind.is_file()) {
                     return false;
                 }
 
@@ -126,14 +341,113 @@ impl Idb {
                 }
 
                 // Process this only if it is present in the extension whitelist
-                valid_exts.contains(&&ext.to_string())
-            }).map(|entry| entry.path()
-                .strip_prefix("./")
-                .unwrap_or(entry.path())
-                .to_path_buf())
+                valid_exts.contains(&ext.to_string())
+            }).map(|entry| strip_root(&root, entry.path()).to_path_buf())
             .collect();
 
-        print_time_stats("Enumeration", now.elapsed());
+        if verbose {
+            print_time_stats("Enumeration", now.elapsed());
+        }
+
+        files
+    }
+
+    /// This function will iterate over the project root and build the search
+    /// database from all the files present in it
+    pub fn iterate_dir(&mut self) {
+        let files = self.enumerate_files(vec![], true);
+        self.index_files(files, HashMap::new(), true);
+    }
+
+    /// Bring an existing database up to date with the files in the project
+    /// root. Only the files that were added or modified since they were indexed
+    /// are read, and the files that no longer exist are dropped.
+    pub fn update(&mut self) {
+        let files = self.enumerate_files(vec![], true);
+        self.sync(files, |_| true, true);
+    }
+
+    /// Bring the parts of the database under `paths` up to date. `paths` are
+    /// absolute paths of files or directories in the project that might have
+    /// changed, such as the ones reported by a filesystem watcher. Only the
+    /// number of changed files is printed, as this runs in the server.
+    pub fn refresh(&mut self, paths: &HashSet<PathBuf>) {
+
+        let paths = paths.iter()
+            .filter_map(|path| path.strip_prefix(&self.project_root).ok())
+            .map(Path::to_path_buf)
+            .collect::<Vec<_>>();
+
+        if paths.is_empty() {
+            return;
+        }
+
+        let files = self.enumerate_files(paths.clone(), false);
+
+        self.sync(files, |file| paths.iter().any(|path| file.starts_with(path)), false);
+    }
+
+    /// Match the files in the database for which `in_scope` returns true with
+    /// the files currently on disk in `files`. Added and modified files are
+    /// indexed and the ones that are no longer present are removed. The
+    /// progress of the indexing is only printed if `verbose` is set.
+    fn sync<F>(&mut self, files: Vec<PathBuf>, in_scope: F, verbose: bool)
+        where F: Fn(&Path) -> bool {
+
+        // Everything that is left in `paths` once all the files on disk have
+        // been matched against it is a file that was removed
+        let mut paths: HashMap<PathBuf, u32> = self.idx_db.iter()
+            .filter(|(_, path)| in_scope(path))
+            .map(|(id, path)| (path.clone(), *id))
+            .collect();
+        let mut changed  = Vec::new();
+        let mut replaced = HashMap::new();
+
+        for file in files {
+            let Some(id) = paths.remove(&file) else {
+                changed.push(file);
+                continue;
+            };
+
+            // A file only needs to be read if its mtime or size changed
+            let old = self.meta_db.get(&id);
+            let modified = match (FileMeta::stat(&self.project_root.join(&file)), old) {
+                (Ok(meta), Some(old)) => meta.mtime != old.mtime || meta.size != old.size,
+                _                     => true,
+            };
+
+            if modified {
+                replaced.insert(file.clone(), id);
+                changed.push(file);
+            }
+        }
+
+        let removed  = paths.len();
+        let added    = changed.len() - replaced.len();
+        let modified = replaced.len();
+
+        paths.into_values().for_each(|id| self.remove_file(id));
+
+        let unchanged = self.index_files(changed, replaced, verbose);
+
+        println!("Files added: {added}, modified: {}, removed: {removed}",
+                 modified - unchanged);
+    }
+
+    /// Read, tokenize and add `files` to the database. The files in `replaced`
+    /// are already in the database with the given ids, they are replaced if
+    /// their contents changed and only get their metadata refreshed otherwise.
+    /// Returns the number of replaced files whose contents did not change. The
+    /// progress is only shown if `verbose` is set.
+    fn index_files(&mut self, files: Vec<PathBuf>,
+                   mut replaced: HashMap<PathBuf, u32>, verbose: bool) -> usize {
+
+        // The hashes of the replaced files, to skip the ones that did not change
+        let hashes: HashMap<PathBuf, u64> = replaced.iter()
+            .filter_map(|(path, id)| {
+                self.meta_db.get(id).map(|meta| (path.clone(), meta.hash))
+            })
+            .collect();
 
         let totalfiles = files.len() as u64;
         let pfiles = Arc::new(AtomicU64::new(0));
@@ -141,16 +455,19 @@ impl Idb {
         let stop = Arc::new(AtomicBool::new(false));
         let stop_clone = Arc::clone(&stop);
 
-        println!("\nFiles to process: {}", files.len());
+        if verbose {
+            println!("\nFiles to process: {}", files.len());
+        }
 
-        let now = Instant::now();
+        let now  = Instant::now();
+        let root = self.project_root.clone();
 
         // We are going to parallelize the iteration. First we spawn a worker thread
         // to do insertion into the database. Then we start iterating over the file
         // we previously collected to read the file contents, split it into trigrams
         // and then send this along with the file name to the worker that inserts
         // this data into the db.
-        rayon::scope(|s| {
+        let touched = rayon::scope(|s| {
 
             // Create a channel for the workers to communicate
             let (token_tx, token_rx) = mpsc::channel();
@@ -158,6 +475,10 @@ impl Idb {
 
             // Spawn a thread to show the progress of the indexing
             s.spawn(move |_| {
+                if !verbose {
+                    return;
+                }
+
                 let dur = std::time::Duration::from_millis(100);
 
                 print!("\x1b[?25l");
@@ -172,14 +493,20 @@ impl Idb {
 
                     stdout().flush().unwrap();
                 }
-                print!("\x1b[?25h");
-                print!("\n");
+                println!("\x1b[?25h");
             });
 
-            // The worker thread to insert data into the database
+            // The worker thread to insert data into the database. Files that
+            // are being replaced are removed right before the new version is
+            // inserted.
+            let db = &mut *self;
+            let replaced = &mut replaced;
             s.spawn(move |_| {
-                token_rx.iter().for_each(|(input, fname)| {
-                    self.update_db(&fname, input);
+                token_rx.iter().for_each(|(input, fname, meta): (Vec<Trigram>, PathBuf, FileMeta)| {
+                    if let Some(id) = replaced.remove(&fname) {
+                        db.remove_file(id);
+                    }
+                    db.update_db(&fname, input, meta);
                     pfiles.fetch_add(1, Ordering::SeqCst);
                 });
                 stop.store(true, Ordering::SeqCst);
@@ -190,96 +517,95 @@ impl Idb {
             s.spawn(move |_| {
                 data_rx.into_iter()
                     .par_bridge()
-                    .for_each_with(token_tx, |token_tx, (data, fname): (String, PathBuf)| {
-                        let idc = get_indices(&data);
-                        if idc.len() < 4 {
-                            return;
-                        }
-
-                        // We are going to use a set to store the trigrams. This
-                        // will prevent duplicate str's and significantly improve
-                        // the time required to insert into the db
-                        let mut key_set = HashSet::new();
-                        (0..idc.len()-3).into_iter().for_each(|i| {
-                            key_set.insert(data[idc[i]..idc[i+3]].to_string());
-                        });
-
-                        token_tx.send((key_set, fname)).unwrap();
+                    .for_each_with(token_tx, |token_tx, (data, fname, meta): (String, PathBuf, FileMeta)| {
+                        // Sort and dedup the trigrams of the file. This will
+                        // significantly improve the time required to insert
+                        // into the db
+                        let mut keys = tokenize(&data);
+                        keys.sort_unstable();
+                        keys.dedup();
+
+                        token_tx.send((keys, fname, meta)).unwrap();
                     });
             });
 
             // Iterate over the collected files, read the data and then and transmit
             // them to the receiver worker.
+            let mut touched = Vec::new();
             for file in files {
 
-                if let Ok(input) = fs::read_to_string(file.as_path()) {
-
-                    // Ignore files too small to tokenize
-                    if input.len() < 3 {
-                        return;
-                    }
-
-                    // Send the trigrams set along with the file name to the
-                    // worker thread for inserting into the db
-                    data_tx.send((input, file.clone())).unwrap();
+                let path = root.join(&file);
+                let (Ok(data), Ok(mut meta)) = (fs::read(&path), FileMeta::stat(&path)) else {
+                    continue;
+                };
+                meta.hash = hash(&data);
+
+                // The file was touched but its contents are the same, so there is
+                // no need to index it again
+                if hashes.get(&file) == Some(&meta.hash) {
+                    touched.push((file, meta));
+                    continue;
                 }
-            }
-        });
-
-        print_time_stats("Indexation", now.elapsed());
-    }
 
-    /// Search for the input string using the provided index. Returns the number of
-    /// lines on which this input was found.
-    pub fn find(&self, input: &str) -> usize {
+                // Binary files are still added to the database, without any
+                // trigrams, so that updates know that they were already seen
+                let input = String::from_utf8(data).unwrap_or_default();
 
-        // Get the files likely to contain the input string
-        let files = self.find_file_names(input);
-        let total = files.len();
+                // Send the file contents along with the file name to the
+                // worker thread for tokenizing and inserting into the db
+                data_tx.send((input, file, meta)).unwrap();
+            }
 
-        let now = Instant::now();
+            touched
+        });
 
-        // Parallely check all the files to see which all contain the input and
-        // sum the total number of lines found in files
-        let found: usize = files.par_iter()
-                                .map(|path| check_file(path, input))
-                                .sum();
+        if verbose {
+            print_time_stats("Indexation", now.elapsed());
+        }
 
-        print_time_stats("Query", now.elapsed());
-        println!("Searched files: {total}");
+        let unchanged = touched.len();
+        for (file, meta) in touched {
+            if let Some(id) = replaced.get(&file) {
+                self.meta_db.insert(*id, meta);
+            }
+        }
 
-        found
+        unchanged
     }
+}
 
-    /// Generates a list of file names which might contain the string passed as
-    /// input
-    pub fn find_file_names(&self, input: &str) -> Vec<PathBuf> {
+impl Index for Idb {
 
-        // Tokenize the input string
-        let tokens = tokenize(input);
+    fn project_root(&self) -> &Path {
+        &self.project_root
+    }
 
-        // Make a list of sets which match contain the input string in the db
-        let mut hits = Vec::new();
-        for token in tokens {
-            if let Some(set) = self.str_db.get(&token) {
-                hits.push(set);
-            } else {
-                return vec![];
-            }
-        }
+    fn postings(&self, key: Trigram) -> Option<Postings<'_>> {
+        self.str_db.get(&key).map(PostingList::as_postings)
+    }
 
-        // Take an intersection of all the sets in the list generated above and
-        // then map the file id back to the original file name
-        // Note: The BitAnd in the fold will create a new set each time. We can
-        // optimize this further if required
-        let intset: HashSet<u32> = hits[0].clone();
+    fn file_name(&self, id: u32) -> Option<PathBuf> {
+        self.idx_db.get(&id).cloned()
+    }
 
-        let found = hits.iter()
-                        .fold(intset, |acc, set| &acc & *set)
-                        .iter()
-                        .filter_map(|id| self.idx_db.get(&id).map(|f| f.clone()))
-                        .collect::<Vec<_>>();
+    fn file_ids(&self) -> Vec<u32> {
+        let mut ids = self.idx_db.keys().copied().collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids
+    }
 
-        found
+    fn short_files(&self) -> Vec<u32> {
+        // The files without metadata, from converted databases, could be short
+        let mut ids = self.idx_db.keys()
+            .filter(|id| self.meta_db.get(id).is_none_or(|meta| meta.size < NGRAM as u64))
+            .copied()
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids
     }
 }
+
+/// Make the paths produced by walking the project `root` relative to it
+fn strip_root<'a>(root: &Path, path: &'a Path) -> &'a Path {
+    path.strip_prefix(root).unwrap_or(path)
+}
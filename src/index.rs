@@ -0,0 +1,166 @@
+use std::time::Instant;
+use std::path::{Path, PathBuf};
+
+use crate::utils::*;
+use crate::query::Query;
+use crate::matcher::Matcher;
+use crate::filter::PathFilter;
+use crate::explain::{Explain, Step};
+use crate::output::{Printer, Summary};
+use crate::postings::{Postings, intersect_all, intersect_sorted, union_all};
+
+/// Common interface of the search databases. This is implemented by the in
+/// memory `Idb` and the memory mapped `MappedIdb` so that both of them can be
+/// queried in the same way.
+pub trait Index {
+
+    /// The root of the project that was indexed
+    fn project_root(&self) -> &Path;
+
+    /// Get the posting list of the files that contain the trigram `key`
+    fn postings(&self, key: Trigram) -> Option<Postings<'_>>;
+
+    /// Map a file id back to the name of the file
+    fn file_name(&self, id: u32) -> Option<PathBuf>;
+
+    /// The ids of all the files in the database, in increasing order
+    fn file_ids(&self) -> Vec<u32>;
+
+    /// The ids of the files shorter than a trigram, in increasing order. These
+    /// are in no posting list, so only the queries for terms that short can
+    /// find them.
+    fn short_files(&self) -> Vec<u32>;
+
+    /// Search for the matches of `matcher` in the files allowed by `filter`
+    /// using the provided index, printing them with `printer`
+    fn find(&self, matcher: &Matcher, filter: &PathFilter, printer: &Printer) -> Summary {
+
+        let now = Instant::now();
+
+        // Get the files likely to contain a match and check them all
+        let (files, mut explain) = self.find_file_names(&matcher.query(), filter,
+                                                        printer.explains());
+        let verify = Instant::now();
+        let mut summary = printer.search(&files, matcher);
+
+        if let Some(explain) = explain.as_mut() {
+            explain.verify_ns = verify.elapsed().as_nanos() as u64;
+        }
+
+        summary.explain = explain;
+        summary.elapsed = now.elapsed();
+        summary
+    }
+
+    /// Generates a list of file names which satisfy `query`, and so might
+    /// contain a match, and which are allowed by `filter`. If `explain` is set
+    /// it also reports how the files were found.
+    fn find_file_names(&self, query: &Query, filter: &PathFilter,
+                       explain: bool) -> (Vec<PathBuf>, Option<Explain>) {
+
+        let now = Instant::now();
+        let (ids, plan) = self.lookup(query, explain);
+        let lookup = now.elapsed();
+
+        let now = Instant::now();
+        let files = ids.iter()
+            .filter_map(|id| self.file_name(*id))
+            .filter(|path| filter.is_match(path))
+            .collect::<Vec<_>>();
+        let filter = now.elapsed();
+
+        let explain = plan.map(|plan| Explain {
+            plan,
+            candidate_files: ids.len(),
+            filtered_files:  files.len(),
+            lookup_ns:       lookup.as_nanos() as u64,
+            filter_ns:       filter.as_nanos() as u64,
+            verify_ns:       0,
+        });
+
+        (files, explain)
+    }
+
+    /// Get the sorted ids of the files which satisfy `query`, along with the
+    /// steps taken to find them if `explain` is set
+    fn lookup(&self, query: &Query, explain: bool) -> (Vec<u32>, Option<Step>) {
+
+        let mut steps = vec![];
+
+        let (op, ids) = match query {
+            Query::All          => ("all files".to_string(), self.file_ids()),
+            Query::None         => ("no files".to_string(), vec![]),
+            Query::Short        => ("short files".to_string(), self.short_files()),
+            Query::Trigram(key) => {
+                let ids = self.postings(*key)
+                    .map(|list| list.decode())
+                    .unwrap_or_default();
+
+                // A lone trigram is a single step
+                let step = explain.then(|| Step::trigram(*key, ids.len(), None));
+                return (ids, step);
+            }
+            Query::And(queries) => ("and".to_string(), 'and: {
+
+                // Intersect the posting lists of all the trigrams, starting from
+                // the rarest one. If any of them is missing then nothing can
+                // match.
+                let mut keys  = Vec::new();
+                let mut lists = Vec::new();
+                let mut rest  = Vec::new();
+                for query in queries {
+                    match query {
+                        Query::Trigram(key) => match self.postings(*key) {
+                            Some(list) => {
+                                keys.push((*key, list.len()));
+                                lists.push(list);
+                            }
+                            None       => {
+                                steps.extend(explain.then(|| Step::trigram(*key, 0, Some(0))));
+                                break 'and vec![];
+                            }
+                        },
+                        query => rest.push(query),
+                    }
+                }
+
+                let mut ids = (!lists.is_empty()).then(|| intersect_all(lists, |idx, left| {
+                    let (key, files) = keys[idx];
+                    steps.extend(explain.then(|| Step::trigram(key, files, Some(left))));
+                }));
+
+                // Then narrow them down with the rest of the sub queries
+                for query in rest {
+                    if ids.as_ref().is_some_and(Vec::is_empty) {
+                        break;
+                    }
+
+                    let (found, step) = self.lookup(query, explain);
+                    ids = Some(match ids {
+                        Some(ids) => intersect_sorted(&ids, &found),
+                        None      => found,
+                    });
+
+                    let left = ids.as_ref().map(Vec::len);
+                    steps.extend(step.map(|step| Step { left, ..step }));
+                }
+
+                ids.unwrap_or_else(|| self.file_ids())
+            }),
+            Query::Or(queries) => {
+                let found = queries.iter()
+                    .map(|query| {
+                        let (ids, step) = self.lookup(query, explain);
+                        steps.extend(step);
+                        ids
+                    })
+                    .collect();
+
+                ("or".to_string(), union_all(found))
+            }
+        };
+
+        let step = explain.then_some(Step { op, files: ids.len(), left: None, steps });
+        (ids, step)
+    }
+}
@@ -1,87 +1,180 @@
-use rayon;
-use rayon::prelude::*;
-
 use std::io::*;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::sync::mpsc;
 use std::thread;
-use std::collections::HashMap;
-use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
 
 mod cli;
 mod network;
 mod utils;
 mod idb;
-
-use idb::Idb;
+mod index;
+mod format;
+mod mapped;
+mod postings;
+mod watch;
+mod query;
+mod matcher;
+mod expr;
+mod output;
+mod filter;
+mod explain;
+
+use idb::{Idb, IndexOptions};
+use index::Index;
+use matcher::Matcher;
+use filter::PathFilter;
+use explain::Explain;
+use output::{Format, Printer};
+use mapped::MappedIdb;
 use utils::*;
 use cli::CLIArgs;
-use network::{Request, Response, Transfer};
+use network::{Listener, Request, Response, Stream, Transfer};
+use watch::{Message, DEBOUNCE, PERSIST_DELAY};
 
 fn cli(args: CLIArgs) {
-    let db = unwrap!(Idb::load(&args.database), "Error while loading db");
+    let db = unwrap!(MappedIdb::open(&args.database), "Error while loading db");
 
-    unwrap!(std::env::set_current_dir(&db.project_root),
+    unwrap!(std::env::set_current_dir(db.project_root()),
             "Unable to change current dir");
 
+    let printer = Printer::new(args.printing);
+    let filter  = unwrap!(PathFilter::new(&args.include, &args.exclude), "Invalid path filter");
+
     loop {
-        print!("> ");
-        std::io::stdout().flush().unwrap();
+        if !is_quiet() {
+            print!("> ");
+            std::io::stdout().flush().unwrap();
+        }
         let mut input = String::new();
         unwrap!(std::io::stdin().read_line(&mut input), "User input failed");
 
         let input = input.strip_suffix("\n").unwrap_or(&input);
 
-        if input.len() < 3 {
+        if input.is_empty() {
             break;
         }
 
-        let found = db.find(input);
-        if found == 0 {
-            println!("{_FAIL}{_BOLD}[!] Not Found{_ENDC}");
-        } else {
-            println!("{_OKGREEN}{_BOLD}Hits: {found}{_ENDC}");
-        }
+        let matcher = match Matcher::new(input, &args.matching) {
+            Ok(matcher) => matcher,
+            Err(err)    => {
+                printer.print_error(&err);
+                continue;
+            }
+        };
+
+        printer.print_summary(&db.find(&matcher, &filter, &printer));
     }
 }
 
+/// Get the path to save the database of the project at `project` to, making
+/// sure that its directory exists
+fn output_path(args: &CLIArgs, project: &Path) -> PathBuf {
+
+    let output = unwrap!(args.output.clone()
+                             .or_else(|| default_db_path(project))
+                             .ok_or("the cache dir is unknown, use --output"),
+                         "Unable to find where to save the database");
+
+    if let Some(dir) = output.parent() {
+        unwrap!(std::fs::create_dir_all(dir), "Unable to create the database dir");
+    }
+
+    output
+}
+
 fn main() {
 
     let args = CLIArgs::new();
 
+    // Keep the output clean for the tools reading it
+    set_quiet(args.printing.format != Format::Text);
+
     if args.mode == "index" {
 
         unwrap!(std::env::set_current_dir(&args.project),
                 "Unable to change current dir");
 
-        let mut db = Idb::new(&args.project);
+        let options = IndexOptions {
+            include_ext: args.include_ext.clone(),
+            no_ignore: args.no_ignore,
+            ..Default::default()
+        };
+        let mut db  = Idb::new(&args.project, options);
 
-        db.iterate_dir(&args.include_ext);
+        db.iterate_dir();
 
         println!("Files Indexed: {}",db.cur_id);
-        unwrap!(db.save(), "Error while serializing and saving the database");
+        let output = output_path(&args, &db.project_root);
+        unwrap!(db.save(&output),
+                "Error while serializing and saving the database");
+
+        println!("Database saved to: {}", output.display());
+
+    } else if args.mode == "update" {
+
+        // The database is saved back after changing into the project root, so
+        // get its absolute path first
+        let path = unwrap!(Path::new(&args.database).canonicalize(),
+                           "Invalid database path");
+
+        let mut db = unwrap!(Idb::load(&args.database), "Error while loading db");
+
+        unwrap!(std::env::set_current_dir(&db.project_root),
+                "Unable to change current dir");
+
+        db.update();
+        db.compact();
+
+        println!("Files Indexed: {}", db.idx_db.len());
+        unwrap!(db.save(&path), "Error while serializing and saving the database");
+
+    } else if args.mode == "convert" {
+
+        // Binary databases of the older formats are migrated by loading them
+        // and saving them back in the current format, in place by default
+        let (db, output) = if format::is_database(Path::new(&args.database)) {
+            let db = unwrap!(Idb::load(&args.database), "Error while loading the database");
+            let output = args.output.clone()
+                .unwrap_or_else(|| PathBuf::from(&args.database));
+            (db, output)
+        } else {
+            let db = unwrap!(Idb::load_json(&args.database),
+                             "Error while loading the JSON database");
+            let output = output_path(&args, &db.project_root);
+            (db, output)
+        };
+
+        unwrap!(db.save(&output), "Error while saving the converted database");
+
+        println!("Converted database saved to: {}", output.display());
 
     } else if args.mode == "cli" {
         cli(args);
     } else if args.mode == "server" {
 
-        let listener = unwrap!(TcpListener::bind("127.0.0.1:4141"),
-                               "Failed to bind to given host/port");
+        let listener = unwrap!(Listener::bind(&args.addr),
+                               format!("Failed to bind to {}", args.addr));
 
-        let mut map: HashMap<String, mpsc::Sender<(String, TcpStream)>>
-            = HashMap::new();
+        let mut map: HashMap<String, mpsc::Sender<Message>> = HashMap::new();
 
         for stream in listener.incoming() {
             let mut stream = unwrap_continue!(stream, "Unable to get stream");
             let req = unwrap_continue!(Request::receive(&mut stream),
                                        "Error Receiving Request");
 
-            if map.get(&req.dbname).is_none() {
+            if !map.contains_key(&req.dbname) {
                 let (tx, rx) = mpsc::channel();
-                map.insert(req.dbname.clone(), tx);
+                map.insert(req.dbname.clone(), tx.clone());
                 let dbname = req.dbname.clone();
+                let (watch, persist) = (args.watch, args.persist);
                 thread::spawn(move || {
-                    handle_connection(dbname, rx);
+                    if watch {
+                        handle_live_connection(dbname, rx, tx, persist);
+                    } else {
+                        handle_connection(dbname, rx);
+                    }
                 });
             };
 
@@ -89,26 +182,39 @@ fn main() {
             let tx = map.get(&req.dbname).unwrap();
 
 
-            if tx.send((req.needle, stream)).is_err() {
-                println!("Lost connection to thread! Error db: {}",
-                         &req.dbname);
+            let dbname = req.dbname.clone();
+            if tx.send(Message::Search(req, stream)).is_err() {
+                println!("Lost connection to thread! Error db: {}", &dbname);
 
-                let _ = map.remove(&req.dbname);
+                let _ = map.remove(&dbname);
             }
         }
 
     } else if args.mode == "search" {
 
-        let database = args.database.clone();
-        let needle   = args.expr;
-        let request  = Request {
-            dbname: database,
-            needle: needle.clone(),
+        let printer = Printer::new(args.printing);
+        let matcher = match Matcher::new(&args.expr, &args.matching) {
+            Ok(matcher) => matcher,
+            Err(err)    => {
+                printer.print_error(&err);
+                std::process::exit(-1);
+            }
         };
 
+        let request = Request {
+            dbname:  args.database.clone(),
+            needle:  args.expr.clone(),
+            options: args.matching,
+            include: args.include.clone(),
+            exclude: args.exclude.clone(),
+            offset:  args.offset,
+            limit:   args.limit,
+            explain: args.printing.explain,
+        };
 
-        let mut stream = unwrap!(TcpStream::connect("127.0.0.1:4141"),
-                                 "Failed to connect to the server");
+
+        let mut stream = unwrap!(Stream::connect(&args.addr),
+                                 format!("Failed to connect to the server at {}", args.addr));
 
         unwrap!(request.send(&mut stream), "Failed to send request to the server");
 
@@ -116,7 +222,7 @@ fn main() {
                            "Failed to receive a response from the server");
 
         if resp.error {
-            println!("Error: {}", resp.message);
+            printer.print_error(&format!("Error: {}", resp.message));
             std::process::exit(-1);
         }
 
@@ -129,47 +235,157 @@ fn main() {
         unwrap!(std::env::set_current_dir(&path), "unable to change cwd");
         let now = Instant::now();
 
-        let found: usize = resp.files
-                               .par_iter()
-                               .map(|path| check_file(&path, &needle))
-                               .sum();
-
-        print_time_stats("Query", now.elapsed());
-        println!("Searched files: {}", resp.files.len());
+        let mut summary = printer.search(&resp.files, &matcher);
 
-        if found == 0 {
-            println!("{_FAIL}{_BOLD}[!] Not Found{_ENDC}");
-        } else {
-            println!("{_OKGREEN}{_BOLD}Hits: {found}{_ENDC}");
-        }
+        summary.candidate_files = resp.total;
+        summary.elapsed = now.elapsed();
+        summary.explain = resp.explain.map(|explain| Explain {
+            verify_ns: summary.elapsed.as_nanos() as u64,
+            ..explain
+        });
+        printer.print_summary(&summary);
 
     }
 
 }
 
-fn handle_connection(dbname: String, rx: mpsc::Receiver<(String, TcpStream)>) {
-    let db = match Idb::load(&dbname) {
+fn handle_connection(dbname: String, rx: mpsc::Receiver<Message>) {
+    let db = match MappedIdb::open(&dbname) {
+        Ok(db)   => db,
+        Err(err) => return reject(&rx, &dbname, err),
+    };
+
+    for message in rx.iter() {
+        if let Message::Search(req, stream) = message {
+            respond(&db, &req, stream);
+        }
+    }
+}
+
+/// Serve the database from memory while watching the project root, so that the
+/// changes to the files are applied to the database as soon as they happen.
+/// Once things quiet down the old ids of the changed files are dropped from the
+/// posting lists, and if `persist` is set the database is saved back to disk.
+fn handle_live_connection(dbname: String, rx: mpsc::Receiver<Message>,
+                          tx: mpsc::Sender<Message>, persist: bool) {
+
+    // The current dir is shared by all the databases of the server, so the
+    // database is saved back to its absolute path
+    let db = Path::new(&dbname).canonicalize()
+        .and_then(|path| Ok((Idb::load(&dbname)?, path)));
+
+    let (mut db, path) = match db {
         Ok(db)   => db,
-        Err(err) => {
-            println!("Error Loading db: {err}");
-            return;
+        Err(err) => return reject(&rx, &dbname, err),
+    };
+
+    let _watcher = match watch::watch(&db.project_root, tx) {
+        Ok(watcher) => watcher,
+        Err(err)    => {
+            let err = Error::other(format!("unable to watch {:?}: {err}", db.project_root));
+            return reject(&rx, &dbname, err);
         }
     };
 
-    let project_root = db.project_root.to_str().unwrap().to_string();
+    let mut pending = HashSet::new();
+    let mut dirty   = false;
+    let mut last_change = Instant::now();
+
+    loop {
+
+        // Wait for the next message, or until it is time to apply or save the
+        // changes that were seen so far
+        let timeout = if !pending.is_empty() {
+            DEBOUNCE.saturating_sub(last_change.elapsed())
+        } else if dirty {
+            PERSIST_DELAY.saturating_sub(last_change.elapsed())
+        } else {
+            Duration::from_secs(3600)
+        };
+
+        match rx.recv_timeout(timeout) {
+            Ok(Message::Changed(paths)) => {
+                pending.extend(paths);
+                last_change = Instant::now();
+            }
+            Ok(Message::Search(req, stream)) => {
+                // Make sure that the search sees everything that changed
+                if !pending.is_empty() {
+                    db.refresh(&std::mem::take(&mut pending));
+                    dirty = true;
+                }
+                respond(&db, &req, stream);
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                if !pending.is_empty() {
+                    db.refresh(&std::mem::take(&mut pending));
+                    dirty = true;
+                } else if dirty {
+                    db.compact();
+                    dirty = false;
+
+                    if persist {
+                        if let Err(err) = db.save(&path) {
+                            // Try again later rather than right away
+                            println!("[-] Error while saving the database: {err}");
+                            dirty = true;
+                            last_change = Instant::now();
+                        }
+                    }
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
+
+/// Report the error that prevented the database from being loaded to all the
+/// clients that are waiting on it
+fn reject(rx: &mpsc::Receiver<Message>, dbname: &str, err: Error) {
+    println!("Error Loading db: {err}");
 
-    std::env::set_current_dir(&db.project_root).unwrap();
-    for (message, mut stream) in rx.iter() {
+    let resp = Response::err(format!("Unable to load {dbname}: {err}"));
+    for message in rx.try_iter() {
+        if let Message::Search(_, mut stream) = message {
+            let _ = resp.send(&mut stream);
+        }
+    }
+}
 
-        // Reject the request if the len of the search string is too small
-        if message.len() < 3 {
-            let resp  = Response::err("Input to short");
-            unwrap_continue!(resp.send(&mut stream), "unable to send resp");
-            continue;
+/// Find the files which can contain a match for the request and send them to
+/// the client
+fn respond<I: Index>(db: &I, req: &Request, mut stream: Stream) {
+
+    let matcher = Matcher::new(&req.needle, &req.options)
+        .map_err(|err| format!("Invalid search term: {err}"))
+        .and_then(|matcher| {
+            let filter = PathFilter::new(&req.include, &req.exclude)
+                .map_err(|err| format!("Invalid path filter: {err}"))?;
+            Ok((matcher, filter))
+        });
+
+    let resp = match matcher {
+        Err(err) => Response::err(err),
+        Ok(_) if req.needle.is_empty() => Response::err("Empty search term"),
+        Ok((matcher, filter)) => {
+            let project_root = db.project_root().to_str().unwrap().to_string();
+
+            // The pages have to be taken from the same order every time
+            let (mut files, explain) = db.find_file_names(&matcher.query(), &filter,
+                                                          req.explain);
+            files.sort_unstable();
+
+            let total = files.len();
+            let files = files.into_iter()
+                .skip(req.offset)
+                .take(req.limit.unwrap_or(usize::MAX))
+                .collect();
+
+            Response { explain, ..Response::new(project_root, files, total) }
         }
+    };
 
-        let found = db.find_file_names(&message);
-        let resp  = Response::new(project_root.clone(), found);
-        unwrap_continue!(resp.send(&mut stream), "unable to send resp");
+    if let Err(err) = resp.send(&mut stream) {
+        println!("[-] unable to send resp: {err}");
     }
 }
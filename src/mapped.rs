@@ -0,0 +1,171 @@
+use memmap2::Mmap;
+
+use std::fs;
+use std::io::*;
+use std::ffi::OsStr;
+use std::time::Instant;
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use std::os::unix::ffi::OsStrExt;
+
+use crate::utils::*;
+use crate::format::*;
+use crate::index::Index;
+use crate::idb::FileMeta;
+use crate::postings::Postings;
+
+/// A read only view of a search database backed by a memory map of the
+/// database file. Nothing is parsed upfront, the trigram dictionary and the file
+/// table are binary searched on every lookup, so opening the database is
+/// instant and only the pages touched by queries are ever read from disk.
+pub struct MappedIdb {
+    map:              Mmap,
+    header:           Header,
+    project_root:     PathBuf,
+    pub meta:         Metadata,
+}
+
+impl MappedIdb {
+
+    /// Memory map the database at `path`
+    pub fn open(path: &String) -> Result<MappedIdb> {
+
+        if !is_quiet() {
+            println!("Mapping database: {path}");
+        }
+
+        let now = Instant::now();
+
+        let file = fs::File::open(path)?;
+
+        // Safety: the database files are never modified in place, they are only
+        // replaced by renaming a new file over them (see `Idb::save`), so the
+        // contents of the mapping can't change under us
+        let map = unsafe { Mmap::map(&file)? };
+        let db  = MappedIdb::from_map(map)?;
+
+        print_time_stats("Loading", now.elapsed());
+
+        Ok(db)
+    }
+
+    /// Read the database in `map`, checking that its sections are in bounds
+    pub fn from_map(map: Mmap) -> Result<MappedIdb> {
+
+        let header = Header::parse(&map)?;
+        let root   = slice(&map, HEADER_SIZE as u64, header.root_len)?;
+        let project_root = PathBuf::from(OsStr::from_bytes(root));
+
+        let meta = slice(&map, HEADER_SIZE as u64 + header.root_len,
+                         header.meta_len)?;
+        let meta = Metadata::parse(meta, header.version)?;
+
+        Ok(MappedIdb { map, header, project_root, meta })
+    }
+
+    /// The next file id that would have been assigned by the indexer
+    pub fn cur_id(&self) -> u32 {
+        self.header.cur_id
+    }
+
+    /// Iterate over all the files in the database along with their metadata
+    pub fn files(&self) -> impl Iterator<Item = (u32, PathBuf, FileMeta)> + '_ {
+        (0..self.header.file_count as usize).filter_map(|idx| {
+            let entry = FileEntry::parse(self.entry(self.header.files_off,
+                                                    FILE_ENTRY_SIZE, idx));
+            let name  = self.file_name_of(&entry)?;
+            Some((entry.id, name, entry.meta))
+        })
+    }
+
+    /// Iterate over all the trigrams in the database along with their posting
+    /// lists
+    pub fn keys(&self) -> impl Iterator<Item = (Trigram, Postings<'_>)> + '_ {
+        (0..self.header.key_count as usize).filter_map(|idx| {
+            let entry = KeyEntry::parse(self.entry(self.header.keys_off,
+                                                   KEY_ENTRY_SIZE, idx));
+            Some((entry.key, self.postings_of(&entry)?))
+        })
+    }
+
+    fn postings_of(&self, entry: &KeyEntry) -> Option<Postings<'_>> {
+        let postings = &self.map[self.header.postings_off as usize..
+                                 self.header.names_off as usize];
+        let bytes = slice(postings, entry.off, entry.size as u64).ok()?;
+
+        Postings::from_bytes(entry.count, bytes)
+    }
+
+    fn file_name_of(&self, entry: &FileEntry) -> Option<PathBuf> {
+        let names = &self.map[self.header.names_off as usize..];
+        let name  = slice(names, entry.name_off, entry.name_len as u64).ok()?;
+
+        Some(PathBuf::from(OsStr::from_bytes(name)))
+    }
+
+    /// Get the `idx`th entry of a table of fixed size entries at `off`
+    fn entry(&self, off: u64, size: usize, idx: usize) -> &[u8] {
+        let start = off as usize + idx * size;
+        &self.map[start..start + size]
+    }
+
+    /// Binary search a table of `count` entries, comparing each one of them to
+    /// the target with `cmp`
+    fn search<F>(&self, off: u64, size: usize, count: u64, cmp: F) -> Option<&[u8]>
+    where F: Fn(&[u8]) -> Ordering {
+
+        let (mut lo, mut hi) = (0usize, count as usize);
+
+        while lo < hi {
+            let mid   = lo + (hi - lo) / 2;
+            let entry = self.entry(off, size, mid);
+
+            match cmp(entry) {
+                Ordering::Less    => lo = mid + 1,
+                Ordering::Greater => hi = mid,
+                Ordering::Equal   => return Some(entry),
+            }
+        }
+
+        None
+    }
+}
+
+impl Index for MappedIdb {
+
+    fn project_root(&self) -> &Path {
+        &self.project_root
+    }
+
+    fn postings(&self, key: Trigram) -> Option<Postings<'_>> {
+
+        let entry = self.search(self.header.keys_off, KEY_ENTRY_SIZE,
+                                self.header.key_count,
+                                |entry| read_u32(entry, 0).cmp(&key))?;
+        self.postings_of(&KeyEntry::parse(entry))
+    }
+
+    fn file_name(&self, id: u32) -> Option<PathBuf> {
+
+        let entry = self.search(self.header.files_off, FILE_ENTRY_SIZE,
+                                self.header.file_count,
+                                |entry| FileEntry::parse(entry).id.cmp(&id))?;
+        self.file_name_of(&FileEntry::parse(entry))
+    }
+
+    fn file_ids(&self) -> Vec<u32> {
+        (0..self.header.file_count as usize)
+            .map(|idx| FileEntry::parse(self.entry(self.header.files_off,
+                                                   FILE_ENTRY_SIZE, idx)).id)
+            .collect()
+    }
+
+    fn short_files(&self) -> Vec<u32> {
+        (0..self.header.file_count as usize)
+            .map(|idx| FileEntry::parse(self.entry(self.header.files_off,
+                                                   FILE_ENTRY_SIZE, idx)))
+            .filter(|entry| entry.meta.size < NGRAM as u64)
+            .map(|entry| entry.id)
+            .collect()
+    }
+}
@@ -0,0 +1,369 @@
+use regex::{Regex, RegexBuilder};
+use regex_syntax::ast::{self, Ast, ClassSetItem, LiteralKind};
+
+use serde::{Deserialize, Serialize};
+
+use std::ops::Range;
+use std::path::Path;
+
+use crate::expr::{self, Expr};
+use crate::query::Query;
+
+/// A single term that is searched for
+pub enum Pattern {
+    /// A plain substring
+    Literal(String),
+    /// A regular expression, matched against every line. Case insensitive
+    /// searches for plain substrings are turned into one of these too.
+    Regex {
+        regex:       Regex,
+        /// The regex was built case insensitive, which the query planner has
+        /// to know about too
+        ignore_case: bool,
+    },
+}
+
+/// What a search looks for in the files: one or more terms, combined with a
+/// boolean expression
+pub struct Matcher {
+    terms:    Vec<Pattern>,
+    expr:     Expr,
+    /// The terms which appear outside of a `NOT`. These are the ones which are
+    /// matched against the lines of the files.
+    positive: Vec<bool>,
+    /// Only match whole words
+    word:     bool,
+}
+
+/// The options of a search which change what is matched
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(default)]
+pub struct MatchOptions {
+    /// The search term is a regular expression
+    pub regex:   bool,
+    /// The search term is a boolean query, see `expr`
+    pub boolean: bool,
+    /// How the case of the letters of the search term is matched
+    pub case:    Case,
+    /// Only match whole words, that is matches which are not part of a larger
+    /// identifier
+    pub word:    bool,
+}
+
+/// The characters besides the alphanumerics and `_` which can be part of an
+/// identifier, by the extensions of the languages
+static IDENT_CHARS: [(&[&str], &str); 3] = [
+    (&["el", "lisp", "cl", "clj", "cljs", "edn", "scm", "ss", "rkt"], "-*!?"),
+    (&["css", "scss", "sass", "less"], "-"),
+    (&["rb"], "?!"),
+];
+
+/// How the case of the letters in the search term is matched
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub enum Case {
+    #[default]
+    Sensitive,
+    Insensitive,
+    /// Case insensitive, unless the search term has an uppercase letter
+    Smart,
+}
+
+impl Case {
+
+    /// Check if the search for `pattern` should ignore case
+    fn ignore(self, pattern: &str, regex: bool) -> bool {
+        match self {
+            Case::Sensitive   => false,
+            Case::Insensitive => true,
+            Case::Smart       => !has_uppercase(pattern, regex),
+        }
+    }
+}
+
+impl Pattern {
+
+    /// Create a pattern for `pattern`, which is a regex if `regex` is set
+    pub fn new(pattern: &str, regex: bool, case: Case) -> Result<Pattern, String> {
+
+        let ignore_case = case.ignore(pattern, regex);
+        if !regex && !ignore_case {
+            return Ok(Pattern::Literal(pattern.to_string()));
+        }
+
+        let pattern = if regex {
+            pattern.to_string()
+        } else {
+            regex::escape(pattern)
+        };
+
+        RegexBuilder::new(&pattern)
+            .case_insensitive(ignore_case)
+            .build()
+            .map(|regex| Pattern::Regex { regex, ignore_case })
+            .map_err(|err| format!("Invalid regex: {err}"))
+    }
+
+    /// The index query for the files that can contain a match
+    pub fn query(&self) -> Query {
+        match self {
+            Pattern::Literal(literal) => Query::term(literal.as_bytes()),
+            Pattern::Regex { regex, ignore_case } => {
+                // The pattern was already parsed by the regex crate, so this
+                // can only fail on the limits of the parser
+                regex_syntax::ParserBuilder::new()
+                    .case_insensitive(*ignore_case)
+                    .build()
+                    .parse(regex.as_str())
+                    .map(|hir| Query::regex(&hir))
+                    .unwrap_or(Query::All)
+            }
+        }
+    }
+
+    /// Check if `line` contains a match. With `word` set only whole words,
+    /// made of the identifier chars in `word`, count.
+    pub fn is_match(&self, line: &str, word: Option<&str>) -> bool {
+        match (self, word) {
+            (_, Some(_))                      => !self.find_iter(line, word).is_empty(),
+            (Pattern::Literal(literal), _)    => line.contains(literal.as_str()),
+            (Pattern::Regex { regex, .. }, _) => regex.is_match(line),
+        }
+    }
+
+    /// Get the byte ranges of all the non empty matches in `line`. With `word`
+    /// set only whole words, made of the identifier chars in `word`, count.
+    pub fn find_iter(&self, line: &str, word: Option<&str>) -> Vec<Range<usize>> {
+        let ranges: Vec<Range<usize>> = match self {
+            Pattern::Literal(literal)    => line.match_indices(literal.as_str())
+                .map(|(start, found)| start..start + found.len())
+                .collect(),
+            Pattern::Regex { regex, .. } => regex.find_iter(line)
+                .map(|found| found.range())
+                .filter(|range| !range.is_empty())
+                .collect(),
+        };
+
+        match word {
+            Some(ident) => ranges.into_iter()
+                .filter(|range| is_word(line, range, ident))
+                .collect(),
+            None        => ranges,
+        }
+    }
+}
+
+impl Matcher {
+
+    /// Create the matcher for the search term `input`
+    pub fn new(input: &str, options: &MatchOptions) -> Result<Matcher, String> {
+
+        if !options.boolean {
+            return Ok(Matcher {
+                terms:    vec![Pattern::new(input, options.regex, options.case)?],
+                expr:     Expr::Term(0),
+                positive: vec![true],
+                word:     options.word,
+            });
+        }
+
+        // Every term of a boolean query is a regex if `regex` is set, and with
+        // smart case the case is picked for every term on its own
+        let (expr, terms) = expr::parse(input)?;
+
+        let terms = terms.iter()
+            .map(|term| Pattern::new(term, options.regex, options.case))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut positive = vec![false; terms.len()];
+        expr.positive(&mut positive);
+
+        if !positive.contains(&true) {
+            return Err("The query needs at least one term that is not negated".to_string());
+        }
+
+        Ok(Matcher { terms, expr, positive, word: options.word })
+    }
+
+    /// The index query for the files that can contain a match
+    pub fn query(&self) -> Query {
+        let terms = self.terms.iter().map(Pattern::query).collect::<Vec<_>>();
+        self.expr.query(&terms)
+    }
+
+    /// Check if the contents of a file `data` satisfy the boolean expression.
+    /// This is always true for a single term, as its lines are checked anyway.
+    /// `ident` are the identifier chars of the file, see `ident_chars`.
+    pub fn is_file_match(&self, data: &str, ident: &str) -> bool {
+        if let Expr::Term(_) = self.expr {
+            return true;
+        }
+
+        let word = self.word.then_some(ident);
+        self.expr.eval(&|idx| {
+            data.split('\n').any(|line| self.terms[idx].is_match(line, word))
+        })
+    }
+
+    /// Check if `line` contains a match of any of the positive terms
+    pub fn is_match(&self, line: &str, ident: &str) -> bool {
+        let word = self.word.then_some(ident);
+        self.positive_terms().any(|term| term.is_match(line, word))
+    }
+
+    /// Get the byte ranges of all the non empty matches of the positive terms
+    /// in `line`. Overlapping matches are merged.
+    pub fn find_iter(&self, line: &str, ident: &str) -> Vec<Range<usize>> {
+        let word = self.word.then_some(ident);
+        let mut ranges = self.positive_terms()
+            .flat_map(|term| term.find_iter(line, word))
+            .collect::<Vec<_>>();
+        ranges.sort_unstable_by_key(|range| (range.start, range.end));
+
+        let mut merged: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+
+        merged
+    }
+
+    fn positive_terms(&self) -> impl Iterator<Item = &Pattern> {
+        self.terms.iter()
+            .zip(&self.positive)
+            .filter(|(_, positive)| **positive)
+            .map(|(term, _)| term)
+    }
+}
+
+/// Check if `pattern` has any uppercase letters. For a regex only the letters
+/// written as they are count, not the ones of escapes like `\S` or `\p{Lu}`,
+/// of group names or of flags.
+fn has_uppercase(pattern: &str, regex: bool) -> bool {
+    if !regex {
+        return pattern.chars().any(char::is_uppercase);
+    }
+
+    // An invalid regex is reported when it is built, whatever the case
+    match ast::parse::Parser::new().parse(pattern) {
+        Ok(ast) => ast::visit(&ast, UppercaseVisitor).is_err(),
+        Err(_)  => pattern.chars().any(char::is_uppercase),
+    }
+}
+
+/// Walks the syntax tree of a regex, and stops with an error at the first
+/// uppercase letter
+struct UppercaseVisitor;
+
+impl UppercaseVisitor {
+
+    fn check(literal: &ast::Literal) -> Result<(), ()> {
+        if literal.kind == LiteralKind::Verbatim && literal.c.is_uppercase() {
+            Err(())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl ast::Visitor for UppercaseVisitor {
+    type Output = ();
+    type Err    = ();
+
+    fn finish(self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn visit_pre(&mut self, ast: &Ast) -> Result<(), ()> {
+        match ast {
+            Ast::Literal(literal) => Self::check(literal),
+            _                     => Ok(()),
+        }
+    }
+
+    fn visit_class_set_item_pre(&mut self, item: &ClassSetItem) -> Result<(), ()> {
+        match item {
+            ClassSetItem::Literal(literal) => Self::check(literal),
+            ClassSetItem::Range(range)     => Self::check(&range.start)
+                .and_then(|()| Self::check(&range.end)),
+            _                              => Ok(()),
+        }
+    }
+}
+
+/// Get the characters besides the alphanumerics and `_` which can be part of an
+/// identifier in the file at `path`, based on its language
+pub fn ident_chars(path: &Path) -> &'static str {
+    let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+    IDENT_CHARS.iter()
+        .find(|(exts, _)| exts.contains(&ext))
+        .map(|(_, chars)| *chars)
+        .unwrap_or_default()
+}
+
+/// Check if the match at `range` in `line` is a whole word, that is if it is
+/// not surrounded by the chars of an identifier
+fn is_word(line: &str, range: &Range<usize>, ident: &str) -> bool {
+    let is_ident = |chr: char| chr.is_alphanumeric() || chr == '_' || ident.contains(chr);
+
+    !line[..range.start].chars().next_back().is_some_and(is_ident)
+        && !line[range.end..].chars().next().is_some_and(is_ident)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ignores(pattern: &str) -> bool {
+        Case::Smart.ignore(pattern, true)
+    }
+
+    #[test]
+    fn smart_case_skips_escapes() {
+        for pattern in ["foo", r"\S+", r"\p{Lu}", r"\P{L}", r"\pL", r"\x{4A}", r"\x4A",
+                        r"\u{1F600}", r"(?P<Name>x)", r"(?U)a+", r"[\p{Greek}a-z]", r"\bfoo\B"] {
+            assert!(ignores(pattern), "{pattern:?} has uppercase");
+        }
+        for pattern in ["Foo", r"\p{Lu}X", r"[A-Z]", r"(?P<name>X)", "É"] {
+            assert!(!ignores(pattern), "{pattern:?} has no uppercase");
+        }
+        assert!(!Case::Smart.ignore(r"\S", false));
+    }
+
+    #[test]
+    fn ignore_case() {
+        for (pattern, regex) in [("foo", false), ("f.o", true), (r"\p{Ll}oo", true)] {
+            let pattern = Pattern::new(pattern, regex, Case::Smart).unwrap();
+            assert!(pattern.is_match("a FOO b", None));
+            assert!(pattern.is_match("a foo b", None));
+        }
+
+        let pattern = Pattern::new("Foo", false, Case::Smart).unwrap();
+        assert!(pattern.is_match("Foo", None));
+        assert!(!pattern.is_match("foo", None));
+    }
+
+    #[test]
+    fn query_follows_the_case() {
+        let parse = |pattern: &str| Query::regex(&regex_syntax::parse(pattern).unwrap());
+
+        let pattern = Pattern::new("foo.bar", true, Case::Insensitive).unwrap();
+        assert_eq!(pattern.query(), parse("(?i)foo.bar"));
+        assert_ne!(pattern.query(), parse("foo.bar"));
+
+        let pattern = Pattern::new("foo.bar", true, Case::Sensitive).unwrap();
+        assert_eq!(pattern.query(), parse("foo.bar"));
+    }
+
+    #[test]
+    fn errors_quote_the_pattern() {
+        for case in [Case::Sensitive, Case::Insensitive, Case::Smart] {
+            let Err(err) = Pattern::new("a(b", true, case) else {
+                panic!("\"a(b\" is a valid regex");
+            };
+            assert!(err.contains("a(b") && !err.contains("(?i)"), "{err}");
+        }
+    }
+}
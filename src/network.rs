@@ -1,9 +1,15 @@
 use serde::{Deserialize, Serialize};
 use std::error;
-use std::net::TcpStream;
-use std::path::PathBuf;
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
 use std::io::{self, ErrorKind, Read, Write};
 
+use crate::matcher::MatchOptions;
+use crate::explain::Explain;
+
 pub trait Transfer {
 
     /// Serialize the contents of this into a string
@@ -18,13 +24,13 @@ pub trait Transfer {
         serde_json::from_str::<Self>(&input)
     }
 
-    /// Receive an instance of this type over the tcp stream `stream`
-    fn receive(stream: &mut TcpStream)
+    /// Receive an instance of this type over the stream `stream`
+    fn receive<S: Read>(stream: &mut S)
                -> std::result::Result<Self, Box<dyn error::Error>> where
         Self: Sized + for<'a> Deserialize<'a> {
 
         let mut pdata = [0u8; 8];
-        stream.read(&mut pdata)?;
+        stream.read_exact(&mut pdata)?;
 
         let size = usize::from_le_bytes(pdata);
 
@@ -42,27 +48,174 @@ pub trait Transfer {
 
     }
 
-    /// Serialize and send this type over the tcp stream `stream`
-    fn send(&self, stream: &mut TcpStream)
+    /// Serialize and send this type over the stream `stream`
+    fn send<S: Write>(&self, stream: &mut S)
             -> std::result::Result<(), Box<dyn error::Error>> where Self: Serialize {
 
         let data = self.pack()?;
         let data = data.as_bytes();
         let size = data.len();
 
-        stream.write(&size.to_le_bytes())?;
-        stream.write(&data)?;
+        stream.write_all(&size.to_le_bytes())?;
+        stream.write_all(data)?;
 
         Ok(())
     }
 }
 
+/// A connection between the client and the server, over TCP or over a Unix
+/// socket
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+/// Accepts the connections of the clients to the server
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Stream {
+
+    /// Connect to the server at `addr`, which is either `host:port` or
+    /// `unix:` followed by the path of a socket
+    pub fn connect(addr: &str) -> io::Result<Stream> {
+        match addr.strip_prefix("unix:") {
+            Some(path) => UnixStream::connect(path).map(Stream::Unix),
+            None       => TcpStream::connect(addr).map(Stream::Tcp),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream)  => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream)  => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream)  => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Listener {
+
+    /// Listen on `addr`, see `Stream::connect`. A Unix socket can only be used
+    /// by its owner: it has to be in a dir of the user that no one else can
+    /// write to, and the missing dirs are created only accessible to the user.
+    /// The socket of a server which is gone is replaced, but nothing else is.
+    pub fn bind(addr: &str) -> io::Result<Listener> {
+        let path = match addr.strip_prefix("unix:") {
+            Some(path) => Path::new(path),
+            None       => return TcpListener::bind(addr).map(Listener::Tcp),
+        };
+
+        if let Ok(meta) = fs::symlink_metadata(path) {
+            if !meta.file_type().is_socket() {
+                return Err(io::Error::new(ErrorKind::AlreadyExists,
+                                          "the path exists and is not a socket"));
+            }
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(ErrorKind::AddrInUse,
+                                          "another server is listening on the socket"));
+            }
+            fs::remove_file(path)?;
+        }
+
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _                                        => Path::new("."),
+        };
+
+        fs::DirBuilder::new().mode(0o700).recursive(true).create(dir)?;
+
+        // Whoever can write to the dir can replace the socket with their own
+        let meta = fs::metadata(dir)?;
+
+        // Safety: geteuid can't fail and has no side effects
+        if meta.uid() != unsafe { libc::geteuid() } {
+            return Err(io::Error::new(ErrorKind::PermissionDenied,
+                                      format!("{} is owned by another user", dir.display())));
+        }
+
+        if meta.mode() & 0o022 != 0 {
+            return Err(io::Error::new(ErrorKind::PermissionDenied,
+                                      format!("{} can be written by other users",
+                                              dir.display())));
+        }
+
+        // The socket is created only accessible to the user, as the dir may
+        // not keep the other users out. This is called before the server
+        // starts any thread, so nothing else sees the umask of the process
+        // change.
+        // Safety: umask can't fail and only changes the mode of new files
+        let umask    = unsafe { libc::umask(0o077) };
+        let listener = UnixListener::bind(path);
+        unsafe { libc::umask(umask) };
+
+        let listener = listener?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+
+        Ok(Listener::Unix(listener))
+    }
+
+    /// Wait for the next client to connect
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener)  => listener.accept().map(|(stream, _)| Stream::Tcp(stream)),
+            Listener::Unix(listener) => listener.accept().map(|(stream, _)| Stream::Unix(stream)),
+        }
+    }
+
+    /// Iterate over the clients as they connect
+    pub fn incoming(&self) -> impl Iterator<Item = io::Result<Stream>> + '_ {
+        std::iter::repeat_with(|| self.accept())
+    }
+}
+
 /// This is a request that will me made by the client process and received by
 /// the server
 #[derive(Serialize, Deserialize)]
 pub struct Request {
     pub dbname: String,
     pub needle: String,
+
+    /// How the needle is matched
+    #[serde(flatten)]
+    pub options: MatchOptions,
+
+    /// Only search the files matching one of these globs, if there are any
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Skip the files matching any of these globs
+    #[serde(default)]
+    pub exclude: Vec<String>,
+
+    /// The number of candidate files to skip, for paging through the results
+    #[serde(default)]
+    pub offset: usize,
+    /// The max number of candidate files to send back, all of them by default
+    #[serde(default)]
+    pub limit: Option<usize>,
+
+    /// Report how the candidate files were found, see `explain`
+    #[serde(default)]
+    pub explain: bool,
 }
 
 /// The response that will be sent by the server to the client process
@@ -71,17 +224,27 @@ pub struct Response {
     pub error: bool,
     pub message: String,
     pub files: Vec<PathBuf>,
+
+    /// The number of candidate files before `offset` and `limit` were applied
+    #[serde(default)]
+    pub total: usize,
+
+    /// How the candidate files were found, if the request asked for it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub explain: Option<Explain>,
 }
 
 impl Transfer for Request {}
 impl Transfer for Response {}
 
 impl Response {
-    pub fn new(message: String, files: Vec<PathBuf>) -> Self {
+    pub fn new(message: String, files: Vec<PathBuf>, total: usize) -> Self {
         Response {
             error: false,
             message,
             files,
+            total,
+            explain: None,
         }
     }
 
@@ -90,6 +253,8 @@ impl Response {
             error: true,
             message: message.as_ref().to_string(),
             files: vec![],
+            total: 0,
+            explain: None,
         }
     }
 }
@@ -0,0 +1,872 @@
+use rayon::prelude::*;
+
+use serde::Serialize;
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{StdoutLock, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::utils::*;
+use crate::matcher::{Matcher, ident_chars};
+use crate::explain::{Explain, Step};
+
+/// The default max width of a line printed on the screen, in bytes. Longer
+/// lines are cut down to snippets around the matches.
+pub const MAX_WIDTH: usize = 100;
+
+/// Marks the text left out of a long line
+const ELLIPSIS: &str = "…";
+
+/// How the search results are printed
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// Colored `path:line:   text` lines for humans
+    #[default]
+    Text,
+    /// One JSON object per line for tools, see `Record`
+    Json,
+    /// `path:line:column:text` for every match, like `rg --vimgrep`
+    Vimgrep,
+    /// Only the paths of the files with a match, like `grep -l`
+    Files,
+    /// `path:count` with the number of matching lines of every file, like
+    /// `grep -c`
+    Count,
+}
+
+/// The order in which the files with a match are printed
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub enum Sort {
+    /// By path. The files are printed as soon as all the files before them
+    /// were checked.
+    #[default]
+    Path,
+    /// The files with the most matching lines first, then by path. Nothing is
+    /// printed until all the files were checked.
+    Rank,
+}
+
+/// The options which change how the search results are printed
+#[derive(Clone, Copy, Default)]
+pub struct PrintOptions {
+    pub format:       Format,
+    pub sort:         Sort,
+    /// Number of lines of context to print before every match
+    pub before:       usize,
+    /// Number of lines of context to print after every match
+    pub after:        usize,
+    /// The width of the snippets which long lines are cut down to, or 0 to
+    /// always print the lines in full
+    pub max_width:    usize,
+    /// End the paths with a NUL byte instead of `:` (or a newline), for the
+    /// paths which contain those
+    pub null:         bool,
+    /// Stop looking for matches in a file after this many matching lines
+    pub max_count:   Option<usize>,
+    /// Stop the search after this many matching lines in total
+    pub max_results: Option<usize>,
+    /// Report how the files to search were found, see `Explain`
+    pub explain:     bool,
+}
+
+/// A line of a file that is printed, either with a match or as context
+pub struct Line<'a> {
+    /// Line number, starting from 1
+    pub lno:     usize,
+    /// Byte offset of the start of the line in the file
+    pub offset:  usize,
+    pub text:    &'a str,
+    pub matched: bool,
+    /// Byte ranges of the matches in the line
+    pub ranges:  Vec<Range<usize>>,
+}
+
+/// The totals of a search
+#[derive(Default)]
+pub struct Summary {
+    /// The files which were read, less than the candidates if the search was
+    /// stopped early by `max_results`
+    pub searched_files:  usize,
+    /// The files which the index says can contain a match
+    pub candidate_files: usize,
+    pub matched_files:   usize,
+    pub matched_lines:   usize,
+    pub elapsed:         Duration,
+    /// How the candidate files were found, with `--explain`
+    pub explain:         Option<Explain>,
+}
+
+/// A checked file as sent to the printer: its position in the sorted list of
+/// files, its number of matching lines and its output
+type Checked = (usize, usize, String);
+
+/// A record of the JSON Lines output
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record<'a> {
+    Match {
+        path:       &'a str,
+        line:       usize,
+        /// Byte column of the first match, starting from 1
+        column:     usize,
+        /// Byte offset of the start of the line in the file
+        offset:     usize,
+        text:       &'a str,
+        submatches: Vec<Submatch<'a>>,
+    },
+    Context {
+        path:   &'a str,
+        line:   usize,
+        offset: usize,
+        text:   &'a str,
+    },
+    Explain {
+        plan:            &'a Step,
+        candidate_files: usize,
+        filtered_files:  usize,
+        searched_files:  usize,
+        matched_files:   usize,
+        /// The files which were read without having a match
+        false_positives: usize,
+        lookup_ns:       u64,
+        filter_ns:       u64,
+        verify_ns:       u64,
+    },
+    Summary {
+        searched_files:  usize,
+        candidate_files: usize,
+        matched_files:   usize,
+        matched_lines:   usize,
+        elapsed_ns:      u64,
+    },
+    Error {
+        message: &'a str,
+    },
+}
+
+/// The max number of sub steps of a step printed by `--explain`, as a two byte
+/// term alone makes hundreds of them
+const MAX_STEPS: usize = 16;
+
+/// A match in a line of the JSON Lines output. The offsets are relative to the
+/// start of the line.
+#[derive(Serialize)]
+struct Submatch<'a> {
+    start: usize,
+    end:   usize,
+    text:  &'a str,
+}
+
+/// Prints the results of a search
+pub struct Printer {
+    options: PrintOptions,
+}
+
+impl Printer {
+
+    pub fn new(options: PrintOptions) -> Printer {
+        Printer { options }
+    }
+
+    /// Check all the `files` for matches of `matcher` in parallel. The files
+    /// are checked in any order, but their matches are printed in the order
+    /// picked by the `sort` option. Once `max_results` matching lines were
+    /// printed the files that are left are skipped.
+    pub fn search(&self, files: &[PathBuf], matcher: &Matcher) -> Summary {
+        let mut files = files.iter().collect::<Vec<_>>();
+        files.sort_unstable();
+
+        let (tx, rx) = mpsc::channel();
+        let stop     = AtomicBool::new(false);
+        let limit    = self.options.max_count.unwrap_or(usize::MAX);
+
+        let (searched_files, totals) = thread::scope(|scope| {
+            let printer = scope.spawn(|| self.print_in_order(rx, &files, matcher, &stop));
+
+            // Every file is sent to the printer, even without a match, so that
+            // it knows when the files before the next one are done
+            let searched_files = files.par_iter()
+                .enumerate()
+                .map_with(tx, |tx, (idx, path)| {
+                    if stop.load(Ordering::Relaxed) {
+                        return 0;
+                    }
+
+                    let (lines, out) = self.check_file(path, matcher, limit);
+                    let _ = tx.send((idx, lines, out));
+                    1
+                })
+                .sum::<usize>();
+
+            (searched_files, printer.join().unwrap_or_default())
+        });
+
+        Summary {
+            searched_files,
+            candidate_files: files.len(),
+            ..totals
+        }
+    }
+
+    /// Print the output of the files received from `rx`, which are numbered by
+    /// their position in the sorted `files`. In path order every file is
+    /// printed as soon as all the ones before it have arrived. It returns the
+    /// totals of what was printed, and sets `stop` once `max_results` is hit.
+    fn print_in_order(&self, rx: mpsc::Receiver<Checked>, files: &[&PathBuf],
+                      matcher: &Matcher, stop: &AtomicBool) -> Summary {
+
+        let mut stdout = std::io::stdout().lock();
+        let mut totals = Summary::default();
+
+        match self.options.sort {
+            Sort::Path => {
+                let mut pending = BTreeMap::new();
+                let mut next    = 0;
+
+                'files: for (idx, lines, out) in rx {
+                    pending.insert(idx, (lines, out));
+                    while let Some((lines, out)) = pending.remove(&next) {
+                        if !self.print_checked(&mut stdout, &mut totals, files[next], matcher,
+                                               lines, out) {
+                            stop.store(true, Ordering::Relaxed);
+                            break 'files;
+                        }
+                        next += 1;
+                    }
+                }
+            }
+            Sort::Rank => {
+                let mut checked = rx.iter().collect::<Vec<_>>();
+                checked.sort_unstable_by_key(|(idx, lines, _)| (Reverse(*lines), *idx));
+
+                for (idx, lines, out) in checked {
+                    if !self.print_checked(&mut stdout, &mut totals, files[idx], matcher,
+                                           lines, out) {
+                        break;
+                    }
+                }
+            }
+        }
+
+        let _ = stdout.flush();
+        totals
+    }
+
+    /// Print the output `out` of a checked file with `lines` matching lines and
+    /// add it to the `totals`. A file which goes over `max_results` is checked
+    /// again for just the results that are left. It returns whether there is
+    /// room for more results.
+    fn print_checked(&self, stdout: &mut StdoutLock, totals: &mut Summary, path: &PathBuf,
+                     matcher: &Matcher, lines: usize, out: String) -> bool {
+
+        let max  = self.options.max_results.unwrap_or(usize::MAX);
+        let left = max - totals.matched_lines;
+
+        let (lines, out) = if lines > left {
+            self.check_file(path, matcher, left)
+        } else {
+            (lines, out)
+        };
+
+        let _ = stdout.write_all(out.as_bytes());
+        totals.matched_files += usize::from(lines > 0);
+        totals.matched_lines += lines;
+
+        totals.matched_lines < max
+    }
+
+    /// Check if the file at `path` contains a match of `matcher` and format the
+    /// first `limit` matching lines. It returns the number of lines on which a
+    /// match was found along with the output for the file.
+    fn check_file(&self, path: &PathBuf, matcher: &Matcher, limit: usize) -> (usize, String) {
+
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(_)   => return (0, String::new())
+        };
+
+        let ident = ident_chars(path);
+        if !matcher.is_file_match(&data, ident) {
+            return (0, String::new());
+        }
+
+        let path = match path.as_path().to_str() {
+            Some(path) => path,
+            None       => return (0, String::new())
+        };
+
+        // The newline at the end of the file doesn't start another line
+        let data  = data.strip_suffix('\n').unwrap_or(&data);
+        let texts = data.split('\n').collect::<Vec<_>>();
+
+        // The lines after the last match that is kept can still be context
+        let mut left = limit;
+        let matched  = texts.iter()
+            .map(|text| {
+                let matched = left > 0 && matcher.is_match(text, ident);
+                left -= usize::from(matched);
+                matched
+            })
+            .collect::<Vec<_>>();
+
+        // Only the formats which print the lines have context
+        let (before, after) = match self.options.format {
+            Format::Text | Format::Json => (self.options.before, self.options.after),
+            _                           => (0, 0),
+        };
+
+        // Take the context around every match, merging the windows which
+        // overlap or touch each other into one group
+        let mut windows: Vec<Range<usize>> = vec![];
+        for idx in (0..texts.len()).filter(|idx| matched[*idx]) {
+            let start = idx.saturating_sub(before);
+            let end   = (idx + after + 1).min(texts.len());
+
+            match windows.last_mut() {
+                Some(last) if start <= last.end => last.end = end,
+                _ => windows.push(start..end),
+            }
+        }
+
+        if windows.is_empty() {
+            return (0, String::new());
+        }
+
+        let mut offsets = Vec::with_capacity(texts.len());
+        texts.iter().fold(0, |offset, text| {
+            offsets.push(offset);
+            offset + text.len() + 1
+        });
+
+        let groups = windows.into_iter()
+            .map(|window| window.map(|idx| Line {
+                lno:     idx + 1,
+                offset:  offsets[idx],
+                text:    texts[idx],
+                matched: matched[idx],
+                ranges:  if matched[idx] { matcher.find_iter(texts[idx], ident) } else { vec![] },
+            }).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        let lines = matched.iter().filter(|matched| **matched).count();
+        (lines, self.format_file(path, &groups))
+    }
+
+    /// Format the `groups` of adjacent lines of the file at `path`
+    fn format_file(&self, path: &str, groups: &[Vec<Line>]) -> String {
+
+        let count = groups.iter().flatten().filter(|line| line.matched).count();
+        let mut out = String::new();
+
+        match self.options.format {
+            Format::Files => out += &format!("{path}{}", self.path_end('\n')),
+            Format::Count => out += &format!("{path}{}{count}\n", self.path_end(':')),
+            _             => {
+                for (idx, group) in groups.iter().enumerate() {
+
+                    // Separate the groups which are not next to each other
+                    // when there is context, like grep
+                    let context = self.options.before > 0 || self.options.after > 0;
+                    if idx > 0 && context && self.options.format == Format::Text {
+                        out += "--\n";
+                    }
+
+                    for line in group {
+                        out += &self.format_line(path, line);
+                    }
+                }
+            }
+        }
+
+        // The text output keeps an empty line between the files
+        if self.options.format == Format::Text {
+            out.push('\n');
+        }
+
+        out
+    }
+
+    /// Format a single line of the output, including the newline
+    fn format_line(&self, path: &str, line: &Line) -> String {
+        match self.options.format {
+            Format::Text => {
+                let text = snippet(line.text, &line.ranges, self.options.max_width);
+
+                // Context lines use `-` instead of `:`, like grep
+                let sep = if line.matched { ':' } else { '-' };
+                format!("{path}{}{}{sep}   {}\n", self.path_end(sep), line.lno, text)
+            }
+            Format::Vimgrep => {
+                // Lines only matched by empty matches still get printed once
+                let columns = match line.ranges.is_empty() {
+                    true  => vec![1],
+                    false => line.ranges.iter().map(|range| range.start + 1).collect(),
+                };
+
+                columns.into_iter()
+                    .map(|column| format!("{path}{}{}:{column}:{}\n",
+                                          self.path_end(':'), line.lno, line.text))
+                    .collect()
+            }
+            // These print one line per file, see `format_file`
+            Format::Files | Format::Count => String::new(),
+            Format::Json if !line.matched => {
+                to_json(&Record::Context {
+                    path,
+                    line:   line.lno,
+                    offset: line.offset,
+                    text:   line.text,
+                })
+            }
+            Format::Json => {
+                let submatches = line.ranges.iter()
+                    .map(|range| Submatch {
+                        start: range.start,
+                        end:   range.end,
+                        text:  &line.text[range.clone()],
+                    })
+                    .collect();
+
+                to_json(&Record::Match {
+                    path,
+                    line:   line.lno,
+                    column: line.ranges.first().map_or(1, |range| range.start + 1),
+                    offset: line.offset,
+                    text:   line.text,
+                    submatches,
+                })
+            }
+        }
+    }
+
+    /// Check if the search should report how the files were found
+    pub fn explains(&self) -> bool {
+        self.options.explain
+    }
+
+    /// The char which follows a path in the output, `sep` unless the paths end
+    /// with a NUL byte
+    fn path_end(&self, sep: char) -> char {
+        if self.options.null { '\0' } else { sep }
+    }
+
+    /// Print the totals of the search once all the files were checked
+    pub fn print_summary(&self, summary: &Summary) {
+        if let Some(explain) = &summary.explain {
+            self.print_explain(explain, summary);
+        }
+
+        match self.options.format {
+            Format::Text => {
+                print_time_stats("Query", summary.elapsed);
+                if summary.searched_files < summary.candidate_files {
+                    println!("Searched files: {} of {}", summary.searched_files,
+                             summary.candidate_files);
+                } else {
+                    println!("Searched files: {}", summary.searched_files);
+                }
+
+                if summary.matched_lines == 0 {
+                    println!("{_FAIL}{_BOLD}[!] Not Found{_ENDC}");
+                } else {
+                    println!("{_OKGREEN}{_BOLD}Hits: {}{_ENDC}", summary.matched_lines);
+                }
+            }
+            Format::Json => {
+                let record = Record::Summary {
+                    searched_files:  summary.searched_files,
+                    candidate_files: summary.candidate_files,
+                    matched_files:   summary.matched_files,
+                    matched_lines:   summary.matched_lines,
+                    elapsed_ns:      summary.elapsed.as_nanos() as u64,
+                };
+                let _ = std::io::stdout().lock().write_all(to_json(&record).as_bytes());
+            }
+            // These are read by other programs, which only expect the matches
+            Format::Vimgrep | Format::Files | Format::Count => {}
+        }
+    }
+
+    /// Print an error which stops a search, like an invalid search term. The
+    /// formats read by other programs only get the JSON record, or else the
+    /// message on stderr.
+    pub fn print_error(&self, message: &str) {
+        match self.options.format {
+            Format::Text => println!("{_FAIL}{_BOLD}[!] {message}{_ENDC}"),
+            Format::Json => {
+                let record = Record::Error { message };
+                let _ = std::io::stdout().lock().write_all(to_json(&record).as_bytes());
+            }
+            Format::Vimgrep | Format::Files | Format::Count => eprintln!("[!] {message}"),
+        }
+    }
+
+    /// Print the report of `--explain`
+    fn print_explain(&self, explain: &Explain, summary: &Summary) {
+        let false_positives = summary.searched_files.saturating_sub(summary.matched_files);
+
+        match self.options.format {
+            Format::Text => {
+                let mut out = String::from("\n==== Query Plan ====\n");
+                format_step(&explain.plan, 0, &mut out);
+
+                let rate = false_positives as f64 * 100.0 / summary.searched_files.max(1) as f64;
+                out += &format!("Candidate files: {}, after the path filters: {}\n",
+                                explain.candidate_files, explain.filtered_files);
+                out += &format!("Files with a match: {} of {} read ({false_positives} \
+                                 false positives, {rate:.1}%)\n",
+                                summary.matched_files, summary.searched_files);
+                out += &format!("Lookup: {:?}, Path filters: {:?}, Verify: {:?}\n",
+                                Duration::from_nanos(explain.lookup_ns),
+                                Duration::from_nanos(explain.filter_ns),
+                                Duration::from_nanos(explain.verify_ns));
+
+                let _ = std::io::stdout().lock().write_all(out.as_bytes());
+            }
+            Format::Json => {
+                let record = Record::Explain {
+                    plan:            &explain.plan,
+                    candidate_files: explain.candidate_files,
+                    filtered_files:  explain.filtered_files,
+                    searched_files:  summary.searched_files,
+                    matched_files:   summary.matched_files,
+                    false_positives,
+                    lookup_ns:       explain.lookup_ns,
+                    filter_ns:       explain.filter_ns,
+                    verify_ns:       explain.verify_ns,
+                };
+                let _ = std::io::stdout().lock().write_all(to_json(&record).as_bytes());
+            }
+            Format::Vimgrep | Format::Files | Format::Count => {}
+        }
+    }
+}
+
+/// Format `step` and its sub steps as an indented tree
+fn format_step(step: &Step, depth: usize, out: &mut String) {
+    let op = format!("{:indent$}{}", "", step.op, indent = depth * 2);
+    *out += &match step.left {
+        Some(left) => format!("{op:<40} files: {:<8} left: {left}\n", step.files),
+        None       => format!("{op:<40} files: {}\n", step.files),
+    };
+
+    for sub in step.steps.iter().take(MAX_STEPS) {
+        format_step(sub, depth + 1, out);
+    }
+
+    if step.steps.len() > MAX_STEPS {
+        *out += &format!("{:indent$}... {} more\n", "", step.steps.len() - MAX_STEPS,
+                         indent = (depth + 1) * 2);
+    }
+}
+
+/// Serialize a record of the JSON Lines output, including the newline
+fn to_json(record: &Record) -> String {
+    // Serializing these plain structs can't fail
+    serde_json::to_string(record).unwrap_or_default() + "\n"
+}
+
+/// Cut `line` down to windows of `width` bytes around every match, with the
+/// matches highlighted. The windows of nearby matches are merged, and the text
+/// left out between them is replaced by an ellipsis. Lines which fit are
+/// printed in full.
+fn snippet(line: &str, matches: &[Range<usize>], width: usize) -> String {
+    if width == 0 || line.len() <= width {
+        return highlight(line, matches);
+    }
+
+    // Center the window on the match, but keep it within the line
+    let mut windows: Vec<Range<usize>> = vec![];
+    for range in matches {
+        let pad   = width.saturating_sub(range.len()) / 2;
+        let start = range.start.saturating_sub(pad).min(line.len() - width);
+        let start = floor_char_boundary(line, start);
+        let end   = floor_char_boundary(line, start + width);
+
+        match windows.last_mut() {
+            Some(last) if start <= last.end => last.end = last.end.max(end),
+            _ => windows.push(start..end),
+        }
+    }
+
+    // Lines without a match are context, show their start
+    if windows.is_empty() {
+        windows.push(0..floor_char_boundary(line, width));
+    }
+
+    let mut out  = String::new();
+    let mut last = 0;
+
+    for window in &windows {
+        if window.start > last {
+            out += ELLIPSIS;
+        }
+
+        // The parts of the matches inside of the window, relative to it
+        let ranges = matches.iter()
+            .filter(|range| range.start < window.end && range.end > window.start)
+            .map(|range| range.start.max(window.start) - window.start
+                 ..range.end.min(window.end) - window.start)
+            .collect::<Vec<_>>();
+
+        out += &highlight(&line[window.clone()], &ranges);
+        last = window.end;
+    }
+
+    if last < line.len() {
+        out += ELLIPSIS;
+    }
+
+    out
+}
+
+/// The last char boundary of `line` at or before `index`, as
+/// `str::floor_char_boundary` which needs a newer Rust
+fn floor_char_boundary(line: &str, index: usize) -> usize {
+    let mut index = index.min(line.len());
+    while !line.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+/// Color the parts of `line` in the byte ranges `matches`
+fn highlight(line: &str, matches: &[Range<usize>]) -> String {
+    let mut out  = String::with_capacity(line.len());
+    let mut last = 0;
+
+    for range in matches {
+        out += &line[last..range.start];
+        out += &format!("{_FAIL}{_BOLD}{}{_ENDC}", &line[range.clone()]);
+        last = range.end;
+    }
+
+    out += &line[last..];
+    out
+}
+
+#[cfg(test)]
+// The matches of a line are slices of ranges, often of a single one
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicUsize;
+
+    use crate::matcher::MatchOptions;
+
+    /// Show the highlighted parts of `text` in brackets
+    fn plain(text: &str) -> String {
+        text.replace(&format!("{_FAIL}{_BOLD}"), "[").replace(_ENDC, "]")
+    }
+
+    fn cut(line: &str, matches: &[Range<usize>], width: usize) -> String {
+        plain(&snippet(line, matches, width))
+    }
+
+    /// The ranges of all the occurrences of `word` in `line`
+    fn find(line: &str, word: &str) -> Vec<Range<usize>> {
+        line.match_indices(word).map(|(start, _)| start..start + word.len()).collect()
+    }
+
+    #[test]
+    fn short_lines_are_kept() {
+        assert_eq!(cut("hello world", &[6..11], 20), "hello [world]");
+        assert_eq!(cut("hello world", &[6..11], 11), "hello [world]");
+        assert_eq!(cut("hello", &[], 5), "hello");
+
+        // A width of 0 never cuts the lines
+        let line = format!("{}x", "a".repeat(500));
+        assert_eq!(cut(&line, &[500..501], 0), format!("{}[x]", "a".repeat(500)));
+    }
+
+    #[test]
+    fn window_around_match() {
+        let line = format!("{}MATCH{}", "a".repeat(50), "b".repeat(50));
+        assert_eq!(cut(&line, &[50..55], 11), "…aaa[MATCH]bbb…");
+
+        // The window stays within the line at both ends
+        let line = format!("MATCH{}", "b".repeat(50));
+        assert_eq!(cut(&line, &[0..5], 11), "[MATCH]bbbbbb…");
+
+        let line = format!("{}MATCH", "a".repeat(50));
+        assert_eq!(cut(&line, &[50..55], 11), "…aaaaaa[MATCH]");
+    }
+
+    #[test]
+    fn windows_are_merged() {
+        // Overlapping windows
+        let line = format!("{}one two{}", "-".repeat(40), "-".repeat(40));
+        assert_eq!(cut(&line, &find(&line, "one").into_iter()
+                                .chain(find(&line, "two")).collect::<Vec<_>>(), 9),
+                   "…---[one] [two]---…");
+
+        // Windows which touch are merged too...
+        let line = format!("{}ab--cd{}", "-".repeat(22), "-".repeat(20));
+        assert_eq!(cut(&line, &[22..24, 26..28], 4), "…-[ab]--[cd]-…");
+
+        // but not windows a byte apart
+        let line = format!("{}ab---cd{}", "-".repeat(22), "-".repeat(20));
+        assert_eq!(cut(&line, &[22..24, 27..29], 4), "…-[ab]-…-[cd]-…");
+
+        // Far apart windows are separated by an ellipsis
+        let line = format!("{}one{}two{}", "-".repeat(20), "-".repeat(40), "-".repeat(20));
+        assert_eq!(cut(&line, &[20..23, 63..66], 5), "…-[one]-…-[two]-…");
+    }
+
+    #[test]
+    fn match_wider_than_width() {
+        let line = format!("{}{}", "x".repeat(30), "-".repeat(70));
+        assert_eq!(cut(&line, &[0..30], 10), format!("[{}]…", "x".repeat(10)));
+
+        let line = format!("{}{}{}", "-".repeat(40), "x".repeat(30), "-".repeat(30));
+        assert_eq!(cut(&line, &[40..70], 10), format!("…[{}]…", "x".repeat(10)));
+    }
+
+    #[test]
+    fn context_lines_show_their_start() {
+        let line = "abcdefghij".repeat(20);
+        assert_eq!(cut(&line, &[], 10), "abcdefghij…");
+    }
+
+    #[test]
+    fn multi_byte_chars() {
+        let line = format!("{}abc{}", "é".repeat(20), "é".repeat(20));
+        assert_eq!(cut(&line, &[40..43], 10), "…éé[abc]é…");
+
+        let line = "é".repeat(100);
+        assert_eq!(cut(&line, &[], 7), format!("{}…", "é".repeat(3)));
+
+        // No window may start or end in the middle of a char
+        let line = "aé日本🦀".repeat(20);
+        let matches = find(&line, "日本");
+        for width in 1..40 {
+            for range in &matches {
+                let out = cut(&line, std::slice::from_ref(range), width);
+                assert!(out.contains("[日本]") || width < 6, "{width}: {out}");
+            }
+            cut(&line, &matches, width);
+            cut(&line, &[], width);
+        }
+    }
+
+    #[test]
+    fn floor_char_boundaries() {
+        assert_eq!(floor_char_boundary("aé", 0), 0);
+        assert_eq!(floor_char_boundary("aé", 2), 1);
+        assert_eq!(floor_char_boundary("aé", 3), 3);
+        assert_eq!(floor_char_boundary("aé", 10), 3);
+    }
+
+    /// Check a file of `lines` for the lines containing `M`, and get the
+    /// number of matching lines with the output, for a file named `f`
+    fn context(lines: &[&str], options: PrintOptions, limit: usize) -> (usize, String) {
+        // The tests run in parallel, so every one of them gets its own file
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!("idfind-context-{}-{}", std::process::id(),
+                                                     NEXT.fetch_add(1, Ordering::Relaxed)));
+        fs::write(&path, lines.join("\n") + "\n").unwrap();
+
+        let matcher = Matcher::new("M", &MatchOptions::default()).unwrap();
+        let printer = Printer::new(options);
+        let (count, out) = printer.check_file(&path, &matcher, limit);
+        let _ = fs::remove_file(&path);
+
+        (count, plain(&out).replace(path.to_str().unwrap(), "f"))
+    }
+
+    fn text(before: usize, after: usize) -> PrintOptions {
+        PrintOptions { before, after, ..PrintOptions::default() }
+    }
+
+    /// Lines `1` to `count`, with a match on the `matches` ones
+    fn numbered(count: usize, matches: &[usize]) -> Vec<String> {
+        (1..=count)
+            .map(|lno| match matches.contains(&lno) {
+                true  => format!("{lno} M"),
+                false => lno.to_string(),
+            })
+            .collect()
+    }
+
+    fn run(count: usize, matches: &[usize], options: PrintOptions,
+           limit: usize) -> (usize, String) {
+        let lines = numbered(count, matches);
+        context(&lines.iter().map(String::as_str).collect::<Vec<_>>(), options, limit)
+    }
+
+    #[test]
+    fn no_context() {
+        assert_eq!(run(10, &[3, 7], text(0, 0), usize::MAX),
+                   (2, "f:3:   3 [M]\nf:7:   7 [M]\n\n".to_string()));
+        assert_eq!(run(10, &[], text(2, 2), usize::MAX), (0, String::new()));
+    }
+
+    #[test]
+    fn overlapping_and_touching_windows() {
+        // Overlapping windows are merged
+        assert_eq!(run(10, &[4, 6], text(1, 1), usize::MAX).1,
+                   "f-3-   3\nf:4:   4 [M]\nf-5-   5\nf:6:   6 [M]\nf-7-   7\n\n");
+
+        // Windows which touch are merged too
+        assert_eq!(run(10, &[3, 6], text(1, 1), usize::MAX).1,
+                   "f-2-   2\nf:3:   3 [M]\nf-4-   4\nf-5-   5\nf:6:   6 [M]\nf-7-   7\n\n");
+
+        // Windows with a line between them are separated
+        assert_eq!(run(10, &[3, 7], text(1, 1), usize::MAX).1,
+                   "f-2-   2\nf:3:   3 [M]\nf-4-   4\n--\nf-6-   6\nf:7:   7 [M]\nf-8-   8\n\n");
+    }
+
+    #[test]
+    fn before_and_after_only() {
+        assert_eq!(run(10, &[5], text(2, 0), usize::MAX).1,
+                   "f-3-   3\nf-4-   4\nf:5:   5 [M]\n\n");
+        assert_eq!(run(10, &[5], text(0, 2), usize::MAX).1,
+                   "f:5:   5 [M]\nf-6-   6\nf-7-   7\n\n");
+    }
+
+    #[test]
+    fn context_at_the_ends_of_the_file() {
+        assert_eq!(run(5, &[1], text(3, 1), usize::MAX).1,
+                   "f:1:   1 [M]\nf-2-   2\n\n");
+
+        // The newline at the end of the file doesn't make another line
+        assert_eq!(run(5, &[5], text(1, 3), usize::MAX).1,
+                   "f-4-   4\nf:5:   5 [M]\n\n");
+    }
+
+    #[test]
+    fn max_count_with_context() {
+        // The matches after the limit are only context
+        assert_eq!(run(10, &[4, 5], text(0, 2), 1),
+                   (1, "f:4:   4 [M]\nf-5-   5 M\nf-6-   6\n\n".to_string()));
+
+        // and the ones further away are not printed at all
+        assert_eq!(run(10, &[2, 8], text(1, 1), 1),
+                   (1, "f-1-   1\nf:2:   2 [M]\nf-3-   3\n\n".to_string()));
+
+        assert_eq!(run(10, &[2, 3, 8], text(0, 1), 2),
+                   (2, "f:2:   2 [M]\nf:3:   3 [M]\nf-4-   4\n\n".to_string()));
+    }
+
+    #[test]
+    fn context_in_other_formats() {
+        let json = PrintOptions { format: Format::Json, ..text(1, 1) };
+        let (_, out) = run(10, &[3, 7], json, usize::MAX);
+        let types = out.lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["type"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(types, ["context", "match", "context", "context", "match", "context"]);
+
+        // The formats for other programs have no context
+        let vimgrep = PrintOptions { format: Format::Vimgrep, ..text(1, 1) };
+        assert_eq!(run(10, &[3], vimgrep, usize::MAX).1, "f:3:3:3 M\n");
+    }
+}
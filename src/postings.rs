@@ -0,0 +1,460 @@
+use std::io::{Result, Write};
+
+use crate::format::read_u32;
+
+/// Number of file ids in each block of a posting list
+pub const BLOCK_SIZE: usize = 64;
+
+/// Size of an entry in the skip table of a posting list
+pub const SKIP_ENTRY_SIZE: usize = 8;
+
+/// A sorted list of file ids, compressed with delta + varint encoding.
+///
+/// The ids are split into blocks of `BLOCK_SIZE` ids. For every block the skip
+/// table holds the first id of the block and the offset of the block in `data`,
+/// and the block itself holds the varint encoded deltas of the rest of its ids.
+/// This lets the intersection jump straight to the block that can contain an id
+/// instead of decoding the whole list.
+#[derive(Default)]
+pub struct PostingList {
+    len:   u32,
+    last:  u32,
+    skips: Vec<u8>,
+    data:  Vec<u8>,
+}
+
+impl PostingList {
+
+    /// Build a posting list from a sorted list of ids
+    pub fn from_sorted<I: IntoIterator<Item = u32>>(ids: I) -> PostingList {
+        let mut list = PostingList::default();
+        ids.into_iter().for_each(|id| list.push(id));
+        list
+    }
+
+    /// Append `id` to the list. The ids have to be pushed in increasing order.
+    pub fn push(&mut self, id: u32) {
+
+        debug_assert!(self.len == 0 || id > self.last, "ids pushed out of order");
+
+        if (self.len as usize).is_multiple_of(BLOCK_SIZE) {
+            self.skips.extend_from_slice(&id.to_le_bytes());
+            self.skips.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        } else {
+            write_varint(&mut self.data, id - self.last);
+        }
+
+        self.last = id;
+        self.len += 1;
+    }
+
+    /// Build a posting list from a borrowed view of one
+    pub fn from_postings(list: Postings) -> PostingList {
+        let last = list.decode_last();
+        PostingList {
+            len:   list.len,
+            last,
+            skips: list.skips.to_vec(),
+            data:  list.data.to_vec(),
+        }
+    }
+
+    /// Remove all the ids for which `keep` returns false
+    pub fn retain<F: Fn(u32) -> bool>(&mut self, keep: F) {
+        let ids = self.as_postings().decode();
+        if ids.iter().all(|id| keep(*id)) {
+            return;
+        }
+
+        *self = PostingList::from_sorted(ids.into_iter().filter(|id| keep(*id)));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Get a borrowed view of this list
+    pub fn as_postings(&self) -> Postings<'_> {
+        Postings {
+            len:   self.len,
+            skips: &self.skips,
+            data:  &self.data,
+        }
+    }
+}
+
+/// A borrowed view of a compressed posting list, either from a `PostingList` in
+/// memory or straight from a memory mapped database
+#[derive(Clone, Copy)]
+pub struct Postings<'a> {
+    len:   u32,
+    skips: &'a [u8],
+    data:  &'a [u8],
+}
+
+impl<'a> Postings<'a> {
+
+    /// Create a view of a serialized posting list of `len` ids. Returns `None` if
+    /// `bytes` is too small to hold the skip table.
+    pub fn from_bytes(len: u32, bytes: &'a [u8]) -> Option<Postings<'a>> {
+        let skips_len = (len as usize).div_ceil(BLOCK_SIZE) * SKIP_ENTRY_SIZE;
+        if bytes.len() < skips_len {
+            return None;
+        }
+
+        let (skips, data) = bytes.split_at(skips_len);
+        Some(Postings { len, skips, data })
+    }
+
+    /// Number of ids in this list
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    /// Size in bytes of the serialized list
+    pub fn size(&self) -> usize {
+        self.skips.len() + self.data.len()
+    }
+
+    /// Serialize the list into `out`
+    pub fn write<W: Write>(&self, out: &mut W) -> Result<()> {
+        out.write_all(self.skips)?;
+        out.write_all(self.data)?;
+        Ok(())
+    }
+
+    fn blocks(&self) -> usize {
+        self.skips.len() / SKIP_ENTRY_SIZE
+    }
+
+    /// The first id of the `block`th block
+    fn first(&self, block: usize) -> u32 {
+        read_u32(self.skips, block * SKIP_ENTRY_SIZE)
+    }
+
+    /// Decode all the ids in the `block`th block into `out`
+    fn decode_block(&self, block: usize, out: &mut Vec<u32>) {
+
+        out.clear();
+
+        let count = (self.len as usize - block * BLOCK_SIZE).min(BLOCK_SIZE);
+        let mut off = read_u32(self.skips, block * SKIP_ENTRY_SIZE + 4) as usize;
+        let mut id  = self.first(block);
+
+        out.push(id);
+        for _ in 1..count {
+            let Some(delta) = read_varint(self.data, &mut off) else {
+                // Truncated list, there is nothing more we can decode
+                return;
+            };
+            id = id.wrapping_add(delta);
+            out.push(id);
+        }
+    }
+
+    /// Decode the whole list
+    pub fn decode(&self) -> Vec<u32> {
+        let mut out   = Vec::with_capacity(self.len());
+        let mut block = Vec::with_capacity(BLOCK_SIZE);
+
+        for idx in 0..self.blocks() {
+            self.decode_block(idx, &mut block);
+            out.extend_from_slice(&block);
+        }
+
+        out
+    }
+
+    /// Decode the last id of the list
+    fn decode_last(&self) -> u32 {
+        let mut block = Vec::with_capacity(BLOCK_SIZE);
+        if let Some(idx) = self.blocks().checked_sub(1) {
+            self.decode_block(idx, &mut block);
+        }
+        block.last().copied().unwrap_or_default()
+    }
+
+    /// Find the last block at or after `from` whose first id is `<= id`. The
+    /// first id of the `from`th block has to be `<= id`. The skip table is
+    /// galloped over so that ids that are close to each other are found quickly
+    /// while far away ones still only need a logarithmic number of steps.
+    fn seek(&self, from: usize, id: u32) -> usize {
+
+        let blocks = self.blocks();
+
+        let mut step = 1;
+        while from + step < blocks && self.first(from + step) <= id {
+            step *= 2;
+        }
+
+        // The answer is now in [from + step/2, min(from + step, blocks))
+        let (mut lo, mut hi) = (from + step / 2, (from + step).min(blocks));
+        while hi - lo > 1 {
+            let mid = lo + (hi - lo) / 2;
+            if self.first(mid) <= id {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+
+        lo
+    }
+
+    /// Intersect this list with the sorted list of ids `ids`
+    pub fn intersect(&self, ids: &[u32]) -> Vec<u32> {
+
+        let mut out = Vec::new();
+        if self.len == 0 {
+            return out;
+        }
+
+        let mut block   = 0;
+        let mut decoded = Vec::with_capacity(BLOCK_SIZE);
+        let mut current = None;
+
+        for &id in ids {
+
+            // This id is before the start of the list
+            if id < self.first(block) {
+                continue;
+            }
+
+            block = self.seek(block, id);
+
+            if current != Some(block) {
+                self.decode_block(block, &mut decoded);
+                current = Some(block);
+            }
+
+            if decoded.binary_search(&id).is_ok() {
+                out.push(id);
+            }
+        }
+
+        out
+    }
+}
+
+/// Intersect all the posting lists in `lists`. The lists are processed from the
+/// rarest to the most common one, so that only the smallest list is ever fully
+/// decoded and the rest are only probed for the ids that are still left. After
+/// every list `step` is called with its index in `lists` and the number of ids
+/// left.
+pub fn intersect_all<F>(lists: Vec<Postings>, mut step: F) -> Vec<u32>
+    where F: FnMut(usize, usize) {
+
+    let mut order = (0..lists.len()).collect::<Vec<_>>();
+    order.sort_unstable_by_key(|idx| lists[*idx].len());
+
+    let mut order = order.into_iter();
+    let mut ids = match order.next() {
+        Some(idx) => {
+            let ids = lists[idx].decode();
+            step(idx, ids.len());
+            ids
+        }
+        None      => return vec![],
+    };
+
+    for idx in order {
+        if ids.is_empty() {
+            break;
+        }
+        ids = lists[idx].intersect(&ids);
+        step(idx, ids.len());
+    }
+
+    ids
+}
+
+/// Intersect the sorted lists of ids `lhs` and `rhs`
+pub fn intersect_sorted(lhs: &[u32], rhs: &[u32]) -> Vec<u32> {
+    let mut out = Vec::new();
+    let (mut i, mut j) = (0, 0);
+
+    while i < lhs.len() && j < rhs.len() {
+        match lhs[i].cmp(&rhs[j]) {
+            std::cmp::Ordering::Less    => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal   => {
+                out.push(lhs[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+
+    out
+}
+
+/// Merge all the sorted lists of ids in `lists` into one sorted list
+pub fn union_all(lists: Vec<Vec<u32>>) -> Vec<u32> {
+    let mut ids = lists.concat();
+    ids.sort_unstable();
+    ids.dedup();
+    ids
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], off: &mut usize) -> Option<u32> {
+    let mut value = 0u32;
+    let mut shift = 0;
+
+    loop {
+        let byte = *data.get(*off)?;
+        *off += 1;
+
+        value |= ((byte & 0x7f) as u32).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sorted ids with gaps of up to `max_gap`, from a fixed seed so that the
+    /// tests are reproducible
+    fn ids(count: usize, max_gap: u32, seed: u64) -> Vec<u32> {
+        let mut state = seed;
+        let mut id    = 0u32;
+
+        (0..count).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            id += 1 + (state >> 33) as u32 % max_gap;
+            id
+        }).collect()
+    }
+
+    /// Serialize `list` and read it back the way a mapped database does
+    fn reload(list: &PostingList) -> (u32, Vec<u8>) {
+        let mut bytes = vec![];
+        list.as_postings().write(&mut bytes).unwrap();
+        (list.len, bytes)
+    }
+
+    #[test]
+    fn round_trip_across_blocks() {
+        let lens = [0, 1, BLOCK_SIZE - 1, BLOCK_SIZE, BLOCK_SIZE + 1,
+                    2 * BLOCK_SIZE, 2 * BLOCK_SIZE + 1, 1000];
+
+        for len in lens {
+            for max_gap in [1, 200, 1 << 20] {
+                let expected = ids(len, max_gap, len as u64);
+                let list     = PostingList::from_sorted(expected.iter().copied());
+                assert_eq!(list.as_postings().decode(), expected);
+
+                let (len, bytes) = reload(&list);
+                let postings = Postings::from_bytes(len, &bytes).unwrap();
+                assert_eq!(postings.len(), expected.len());
+                assert_eq!(postings.size(), bytes.len());
+                assert_eq!(postings.decode(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_extreme_ids() {
+        let expected = vec![0, 1, 127, 128, 16383, 16384, u32::MAX / 2, u32::MAX - 1, u32::MAX];
+        let list     = PostingList::from_sorted(expected.iter().copied());
+        assert_eq!(list.as_postings().decode(), expected);
+    }
+
+    #[test]
+    fn from_bytes_rejects_short_skip_table() {
+        let list = PostingList::from_sorted(ids(BLOCK_SIZE + 1, 10, 1));
+        let (len, bytes) = reload(&list);
+        assert!(Postings::from_bytes(len, &bytes[..SKIP_ENTRY_SIZE]).is_none());
+    }
+
+    #[test]
+    fn push_after_reload_continues_the_block() {
+        for len in [1, BLOCK_SIZE - 1, BLOCK_SIZE, BLOCK_SIZE + 3] {
+            let mut expected = ids(len + 10, 50, 7);
+            let extra        = expected.split_off(len);
+
+            let mut list = PostingList::from_postings(
+                PostingList::from_sorted(expected.iter().copied()).as_postings());
+            extra.iter().for_each(|id| list.push(*id));
+            expected.extend(extra);
+
+            assert_eq!(list.as_postings().decode(), expected);
+        }
+    }
+
+    #[test]
+    fn retain() {
+        let all      = ids(300, 5, 3);
+        let mut list = PostingList::from_sorted(all.iter().copied());
+        list.retain(|id| id % 3 != 0);
+
+        let expected = all.into_iter().filter(|id| id % 3 != 0).collect::<Vec<_>>();
+        assert_eq!(list.as_postings().decode(), expected);
+
+        list.retain(|_| false);
+        assert!(list.is_empty());
+        assert_eq!(list.as_postings().decode(), Vec::<u32>::new());
+    }
+
+    fn naive_intersect(lhs: &[u32], rhs: &[u32]) -> Vec<u32> {
+        lhs.iter().copied().filter(|id| rhs.contains(id)).collect()
+    }
+
+    #[test]
+    fn intersect_matches_naive() {
+        // Dense and sparse lists, so that the probes both stay in a block and
+        // jump over many of them
+        let cases = [(1000, 3, 200, 15), (1000, 3, 20, 150), (50, 2, 2000, 2),
+                     (0, 1, 100, 3), (100, 3, 0, 1), (BLOCK_SIZE, 1, BLOCK_SIZE, 1)];
+
+        for (seed, (len, gap, probes, probe_gap)) in cases.into_iter().enumerate() {
+            let list  = ids(len, gap, seed as u64);
+            let probe = ids(probes, probe_gap, seed as u64 + 100);
+
+            let postings = PostingList::from_sorted(list.iter().copied());
+            assert_eq!(postings.as_postings().intersect(&probe),
+                       naive_intersect(&list, &probe));
+            assert_eq!(intersect_sorted(&list, &probe), naive_intersect(&list, &probe));
+        }
+    }
+
+    #[test]
+    fn intersect_all_matches_naive() {
+        let lists = [ids(2000, 2, 1), ids(300, 10, 2), ids(700, 4, 3)];
+        let owned = lists.iter()
+            .map(|ids| PostingList::from_sorted(ids.iter().copied()))
+            .collect::<Vec<_>>();
+
+        let mut steps = vec![];
+        let found = intersect_all(owned.iter().map(PostingList::as_postings).collect(),
+                                  |idx, left| steps.push((idx, left)));
+
+        let expected = naive_intersect(&naive_intersect(&lists[0], &lists[1]), &lists[2]);
+        assert_eq!(found, expected);
+
+        // The rarest list goes first and the last step leaves the result
+        assert_eq!(steps.len(), 3);
+        assert_eq!(steps[0], (1, lists[1].len()));
+        assert_eq!(steps[2].1, expected.len());
+
+        assert_eq!(intersect_all(vec![], |_, _| ()), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn union_all_merges_and_dedups() {
+        assert_eq!(union_all(vec![vec![1, 5, 9], vec![2, 5], vec![], vec![9, 10]]),
+                   vec![1, 2, 5, 9, 10]);
+    }
+}
@@ -0,0 +1,512 @@
+//! Planning of the index lookups for a search. A search is turned into a boolean
+//! `Query` over trigrams, which is true for every file that can contain a match.
+//! For regular expressions this follows the approach of Google Code Search: the
+//! regex is walked bottom up, keeping track of the strings that its matches can
+//! be, start with and end with, and those are turned into trigram queries.
+
+use regex_syntax::hir::{Class, Hir, HirKind};
+
+use std::collections::BTreeSet;
+
+use crate::utils::*;
+
+/// Maximum number of strings tracked in any of the sets of an `Info`. Larger
+/// sets are turned into queries or trimmed, as they quickly blow up otherwise.
+const MAX_SET: usize = 16;
+
+/// Maximum number of strings made when joining two sets. These are trimmed back
+/// down to `MAX_SET` strings right away.
+const MAX_CROSS: usize = MAX_SET * MAX_SET;
+
+/// A boolean query over the trigrams of the index
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Query {
+    /// Every file can match
+    All,
+    /// No file can match
+    None,
+    /// Only the files containing the trigram can match
+    Trigram(Trigram),
+    /// Only the files shorter than a trigram, which are in no posting list
+    Short,
+    And(Vec<Query>),
+    Or(Vec<Query>),
+}
+
+impl Query {
+
+    /// The query for the files that can contain `literal`. Literals too short to
+    /// hold a trigram can be anywhere.
+    pub fn literal(literal: &[u8]) -> Query {
+        let trigrams = literal.windows(NGRAM)
+            .map(trigram)
+            .collect::<BTreeSet<_>>();
+
+        trigrams.into_iter()
+            .map(Query::Trigram)
+            .fold(Query::All, Query::and)
+    }
+
+    /// The query for the files that can contain the search term `term`. Unlike
+    /// `literal` this also narrows down the files for two byte terms, through
+    /// all the trigrams which start or end with them, and the files too short
+    /// to have any trigram. A single byte can be in too many trigrams, so those
+    /// have to look at all the files.
+    pub fn term(term: &[u8]) -> Query {
+        if term.len() != NGRAM - 1 {
+            return Query::literal(term);
+        }
+
+        let trigrams = (0..=u8::MAX)
+            .flat_map(|byte| [[byte, term[0], term[1]], [term[0], term[1], byte]])
+            .map(|bytes| trigram(&bytes))
+            .collect::<BTreeSet<_>>();
+
+        let mut queries = trigrams.into_iter().map(Query::Trigram).collect::<Vec<_>>();
+        queries.push(Query::Short);
+        Query::Or(queries)
+    }
+
+    /// The query for the files that can contain any of the strings in `set`
+    fn any_of(set: &BTreeSet<Vec<u8>>) -> Query {
+        set.iter()
+            .map(|literal| Query::literal(literal))
+            .fold(Query::None, Query::or)
+    }
+
+    /// Like `any_of`, but looking up the two byte strings with `term`
+    fn any_term(set: &BTreeSet<Vec<u8>>) -> Query {
+        set.iter()
+            .map(|term| Query::term(term))
+            .fold(Query::None, Query::or)
+    }
+
+    pub fn and(self, other: Query) -> Query {
+        match (self, other) {
+            (Query::None, _) | (_, Query::None) => Query::None,
+            (Query::All, query) | (query, Query::All) => query,
+            (Query::And(mut lhs), Query::And(rhs)) => {
+                lhs.extend(rhs);
+                Query::And(dedup(lhs))
+            }
+            (Query::And(mut list), query) | (query, Query::And(mut list)) => {
+                list.push(query);
+                Query::And(dedup(list))
+            }
+            (lhs, rhs) if lhs == rhs => lhs,
+            (lhs, rhs) => Query::And(vec![lhs, rhs]),
+        }
+    }
+
+    pub fn or(self, other: Query) -> Query {
+        match (self, other) {
+            (Query::All, _) | (_, Query::All) => Query::All,
+            (Query::None, query) | (query, Query::None) => query,
+            (Query::Or(mut lhs), Query::Or(rhs)) => {
+                lhs.extend(rhs);
+                Query::Or(dedup(lhs))
+            }
+            (Query::Or(mut list), query) | (query, Query::Or(mut list)) => {
+                list.push(query);
+                Query::Or(dedup(list))
+            }
+            (lhs, rhs) if lhs == rhs => lhs,
+            (lhs, rhs) => Query::Or(vec![lhs, rhs]),
+        }
+    }
+
+    /// The query for the files that can contain a match of the regex `hir`
+    pub fn regex(hir: &Hir) -> Query {
+        Info::analyze(hir).query()
+    }
+}
+
+fn dedup(mut list: Vec<Query>) -> Vec<Query> {
+    list.sort_unstable();
+    list.dedup();
+    list
+}
+
+/// What is known about the strings matched by a regex
+struct Info {
+    /// All the strings that the regex can match, if there are few of them
+    exact:  Option<BTreeSet<Vec<u8>>>,
+    /// Every match starts with one of these. Only used if `exact` is not known.
+    prefix: BTreeSet<Vec<u8>>,
+    /// Every match ends with one of these. Only used if `exact` is not known.
+    suffix: BTreeSet<Vec<u8>>,
+    /// A query that every match satisfies on top of the sets above
+    query:  Query,
+}
+
+impl Info {
+
+    /// The regex matches exactly the strings in `set`
+    fn exact(set: BTreeSet<Vec<u8>>) -> Info {
+        Info {
+            exact:  Some(set),
+            prefix: BTreeSet::new(),
+            suffix: BTreeSet::new(),
+            query:  Query::All,
+        }
+    }
+
+    /// The regex can match anything
+    fn any() -> Info {
+        Info {
+            exact:  None,
+            prefix: BTreeSet::from([vec![]]),
+            suffix: BTreeSet::from([vec![]]),
+            query:  Query::All,
+        }
+    }
+
+    fn analyze(hir: &Hir) -> Info {
+        let info = match hir.kind() {
+            HirKind::Empty | HirKind::Look(_) => Info::exact(BTreeSet::from([vec![]])),
+            HirKind::Literal(literal) => Info::exact(BTreeSet::from([literal.0.to_vec()])),
+            HirKind::Class(class) => match class_strings(class) {
+                Some(set) => Info::exact(set),
+                None      => Info::any(),
+            },
+            HirKind::Capture(capture) => Info::analyze(&capture.sub),
+            HirKind::Repetition(rep) => {
+                if rep.min == 0 && rep.max == Some(1) {
+                    Info::analyze(&rep.sub).alternate(Info::exact(BTreeSet::from([vec![]])))
+                } else if rep.min == 0 {
+                    Info::any()
+                } else if rep.max == Some(1) {
+                    Info::analyze(&rep.sub)
+                } else {
+                    // Every match starts and ends with a match of the repeated
+                    // regex, and contains at least one of them
+                    let sub = Info::analyze(&rep.sub);
+                    Info {
+                        prefix: sub.exact.clone().unwrap_or(sub.prefix),
+                        suffix: sub.exact.unwrap_or(sub.suffix),
+                        query:  sub.query,
+                        exact:  None,
+                    }
+                }
+            }
+            HirKind::Concat(subs) => subs.iter()
+                .map(Info::analyze)
+                .reduce(|lhs, rhs| lhs.concat(rhs).simplify())
+                .unwrap_or_else(Info::any),
+            HirKind::Alternation(subs) => subs.iter()
+                .map(Info::analyze)
+                .reduce(|lhs, rhs| lhs.alternate(rhs).simplify())
+                .unwrap_or_else(Info::any),
+        };
+
+        info.simplify()
+    }
+
+    /// The info of the concatenation of the regexes described by `self` and
+    /// `other`
+    fn concat(self, other: Info) -> Info {
+
+        let query = self.query.clone().and(other.query.clone());
+
+        let lhs_end   = self.exact.as_ref().unwrap_or(&self.suffix);
+        let rhs_start = other.exact.as_ref().unwrap_or(&other.prefix);
+
+        if let (Some(lhs), Some(rhs)) = (&self.exact, &other.exact) {
+            if let Some(exact) = cross(lhs, rhs) {
+                return Info { query, ..Info::exact(exact) };
+            }
+        }
+
+        // The trigrams spanning the boundary of the two regexes are known if
+        // there are few enough strings on both sides of it
+        let query = match cross(lhs_end, rhs_start) {
+            Some(boundary) => query.and(Query::any_of(&boundary)),
+            None           => query.and(Query::any_of(lhs_end))
+                                   .and(Query::any_of(rhs_start)),
+        };
+
+        let prefix = match &self.exact {
+            Some(exact) => cross(exact, rhs_start).unwrap_or_else(|| exact.clone()),
+            None        => self.prefix.clone(),
+        };
+
+        let suffix = match &other.exact {
+            Some(exact) => cross(lhs_end, exact).unwrap_or_else(|| exact.clone()),
+            None        => other.suffix.clone(),
+        };
+
+        Info { exact: None, prefix, suffix, query }
+    }
+
+    /// The info of the alternation of the regexes described by `self` and
+    /// `other`
+    fn alternate(self, other: Info) -> Info {
+
+        if let (Some(lhs), Some(rhs)) = (&self.exact, &other.exact) {
+            let query = self.query.clone().or(other.query.clone());
+            return Info { query, ..Info::exact(lhs | rhs) };
+        }
+
+        let query  = self.query.or(other.query);
+        let prefix = self.exact.as_ref().unwrap_or(&self.prefix)
+            | other.exact.as_ref().unwrap_or(&other.prefix);
+        let suffix = self.exact.as_ref().unwrap_or(&self.suffix)
+            | other.exact.as_ref().unwrap_or(&other.suffix);
+
+        Info { exact: None, prefix, suffix, query }
+    }
+
+    /// Keep the sets small by turning the large ones into queries
+    fn simplify(mut self) -> Info {
+
+        if let Some(exact) = self.exact.take_if(|exact| exact.len() > MAX_SET) {
+            self.query  = self.query.and(Query::any_of(&exact));
+            self.prefix = exact.clone();
+            self.suffix = exact;
+        }
+
+        if self.exact.is_none() {
+            // The trigrams in the strings are kept in the query, and the
+            // strings are then cut down until there are few enough of them
+            if self.prefix.len() > MAX_SET {
+                self.query  = self.query.and(Query::any_of(&self.prefix));
+                self.prefix = trim(&self.prefix, |s, len| &s[..s.len().min(len)]);
+            }
+            if self.suffix.len() > MAX_SET {
+                self.query  = self.query.and(Query::any_of(&self.suffix));
+                self.suffix = trim(&self.suffix, |s, len| &s[s.len().saturating_sub(len)..]);
+            }
+        }
+
+        self
+    }
+
+    /// The query that every match of the regex satisfies. If all the matches
+    /// are known they are looked up as search terms, so that short ones like
+    /// the case variants of `(?i)fd` still narrow down the files.
+    fn query(self) -> Query {
+        match &self.exact {
+            Some(exact) => self.query.and(Query::any_term(exact)),
+            None        => self.query
+                .and(Query::any_of(&self.prefix))
+                .and(Query::any_of(&self.suffix)),
+        }
+    }
+}
+
+/// All the strings made of a string of `lhs` followed by one of `rhs`, unless
+/// there are too many of them
+fn cross(lhs: &BTreeSet<Vec<u8>>, rhs: &BTreeSet<Vec<u8>>) -> Option<BTreeSet<Vec<u8>>> {
+    if lhs.len() * rhs.len() > MAX_CROSS {
+        return None;
+    }
+
+    Some(lhs.iter()
+         .flat_map(|lhs| rhs.iter().map(move |rhs| [lhs.as_slice(), rhs].concat()))
+         .collect())
+}
+
+/// Shorten the strings in `set` to at most `NGRAM - 1` bytes with `keep`, and
+/// then further until there are at most `MAX_SET` of them
+fn trim<F>(set: &BTreeSet<Vec<u8>>, keep: F) -> BTreeSet<Vec<u8>>
+    where F: Fn(&[u8], usize) -> &[u8] {
+
+    let mut len = NGRAM - 1;
+    loop {
+        let trimmed = set.iter()
+            .map(|s| keep(s, len).to_vec())
+            .collect::<BTreeSet<_>>();
+
+        if trimmed.len() <= MAX_SET || len == 0 {
+            return trimmed;
+        }
+        len -= 1;
+    }
+}
+
+/// The encodings of all the characters in `class`, unless there are too many
+fn class_strings(class: &Class) -> Option<BTreeSet<Vec<u8>>> {
+    let mut set = BTreeSet::new();
+
+    match class {
+        Class::Unicode(class) => {
+            for range in class.iter() {
+                for chr in range.start()..=range.end() {
+                    if set.len() >= MAX_SET {
+                        return None;
+                    }
+                    set.insert(chr.to_string().into_bytes());
+                }
+            }
+        }
+        Class::Bytes(class) => {
+            for range in class.iter() {
+                for byte in range.start()..=range.end() {
+                    if set.len() >= MAX_SET {
+                        return None;
+                    }
+                    set.insert(vec![byte]);
+                }
+            }
+        }
+    }
+
+    Some(set)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use regex::Regex;
+
+    /// Check if a file with the contents `text` satisfies `query`
+    fn eval(query: &Query, text: &str) -> bool {
+        match query {
+            Query::All          => true,
+            Query::None         => false,
+            Query::Trigram(key) => tokenize(text).contains(key),
+            Query::Short        => text.len() < NGRAM,
+            Query::And(queries) => queries.iter().all(|query| eval(query, text)),
+            Query::Or(queries)  => queries.iter().any(|query| eval(query, text)),
+        }
+    }
+
+    fn plan(pattern: &str) -> Query {
+        Query::regex(&regex_syntax::parse(pattern).unwrap())
+    }
+
+    /// Check that every file containing a match of `pattern` is found by its
+    /// query, with every one of `matches` on its own and in some text, and that
+    /// none of the files in `misses` are. Returns the query.
+    fn check(pattern: &str, matches: &[&str], misses: &[&str]) -> Query {
+        let regex = Regex::new(pattern).unwrap();
+        let query = plan(pattern);
+
+        for found in matches {
+            for text in [found.to_string(), format!("let x = {found};\n")] {
+                assert!(regex.is_match(&text), "{pattern:?} doesn't match {text:?}");
+                assert!(eval(&query, &text), "{pattern:?} misses {text:?} with {query:?}");
+            }
+        }
+
+        for text in misses {
+            assert!(!eval(&query, text), "{pattern:?} doesn't rule out {text:?}");
+        }
+
+        query
+    }
+
+    #[test]
+    fn literal() {
+        assert_eq!(Query::literal(b"ab"), Query::All);
+        assert_eq!(Query::literal(b"abc"), Query::Trigram(trigram(b"abc")));
+        assert_eq!(Query::literal(b"abcd"),
+                   Query::And(vec![Query::Trigram(trigram(b"abc")),
+                                   Query::Trigram(trigram(b"bcd"))]));
+        // Repeated trigrams are only looked up once
+        assert_eq!(Query::literal(b"aaaa"), Query::Trigram(trigram(b"aaa")));
+    }
+
+    #[test]
+    fn term() {
+        assert_eq!(Query::term(b"a"), Query::All);
+        assert_eq!(Query::term(b"abc"), Query::literal(b"abc"));
+
+        let query = Query::term(b"fd");
+        for text in ["fd", "fdx", "xfd", "the fd is", "fd\n"] {
+            assert!(eval(&query, text), "misses {text:?}");
+        }
+        for text in ["f d", "dfx", "fx d"] {
+            assert!(!eval(&query, text), "doesn't rule out {text:?}");
+        }
+        // The files too short for a trigram can't be ruled out
+        for text in ["df", "f", ""] {
+            assert!(eval(&query, text), "misses {text:?}");
+        }
+    }
+
+    #[test]
+    fn and_or_simplify() {
+        let abc = Query::Trigram(trigram(b"abc"));
+        let bcd = Query::Trigram(trigram(b"bcd"));
+
+        assert_eq!(abc.clone().and(Query::All), abc);
+        assert_eq!(abc.clone().and(Query::None), Query::None);
+        assert_eq!(abc.clone().or(Query::All), Query::All);
+        assert_eq!(abc.clone().or(Query::None), abc);
+        assert_eq!(abc.clone().and(abc.clone()), abc);
+        assert_eq!(bcd.clone().or(abc.clone()).or(bcd.clone()),
+                   Query::Or(vec![abc, bcd]));
+    }
+
+    #[test]
+    fn regex_literals() {
+        check("hello", &["hello"], &["help", "hell o"]);
+        check("hello world", &["hello world"], &["hello", "world hello"]);
+    }
+
+    #[test]
+    fn regex_alternation() {
+        let query = check("(get|set)Value", &["getValue", "setValue"],
+                          &["Value", "putValue", "get Value"]);
+        assert_ne!(query, Query::All);
+
+        check("foo|barbaz", &["foo", "barbaz"], &["bar baz", "fo o"]);
+        check("a(bc|de)f", &["abcf", "adef"], &["abc", "abdef"]);
+        check("(open|close)_(file|dir)", &["open_file", "open_dir", "close_file", "close_dir"],
+              &["open file", "_file"]);
+        // An empty branch can match anywhere
+        assert_eq!(plan("abc|"), Query::All);
+    }
+
+    #[test]
+    fn regex_optional() {
+        check("colou?r", &["color", "colour"], &["colr", "colouur"]);
+        check("https?://", &["http://", "https://"], &["http:/", "ftp://"]);
+        check("(abc)?def", &["def", "abcdef"], &["de f"]);
+    }
+
+    #[test]
+    fn regex_repetitions() {
+        check("ab+c", &["abc", "abbc", "abbbbbbbc"], &[]);
+        check("xab+cy", &["xabcy", "xabbbcy"], &["xacy", "xab cy"]);
+        check("x(abc)+y", &["xabcy", "xabcabcy", "xabcabcabcy"], &["xy", "abc"]);
+        check("(abc){2,}", &["abcabc", "abcabcabc"], &["ab c"]);
+        check("a{3,}", &["aaa", "aaaaaaa"], &[]);
+        check("ab{2,4}c", &["abbc", "abbbc", "abbbbc"], &[]);
+        check("foo\\d+bar", &["foo1bar", "foo123bar"], &["fo obar", "foo ba r"]);
+        check("foo.*bar", &["foobar", "foo and bar"], &["fo obar", "foo ba r"]);
+        check("fn +main", &["fn main", "fn    main"], &["fn", "main"]);
+
+        // Nothing is required from repetitions which can be empty
+        assert_eq!(plan("a*"), Query::All);
+        check("abc(def)*", &["abc", "abcdef", "abcdefdef"], &["ab"]);
+    }
+
+    #[test]
+    fn regex_classes() {
+        check("[bc]at", &["bat", "cat"], &["hat", "at"]);
+        check("[0-9]{4}-[0-9]{2}", &["2024-01", "1999-12"], &["2024 01"]);
+        check("[a-z]+ing", &["string", "testing"], &["in g", "testin g"]);
+        check("\\bmain\\(", &["main(", "fn main()"], &["main ("]);
+        check("[^ ]foo", &["xfoo", "afoo"], &["fo o"]);
+
+        // Classes too large to enumerate still keep the literal parts
+        let query = check("\\w+_test", &["foo_test", "x_test"], &["footest"]);
+        assert_ne!(query, Query::All);
+        assert_eq!(plan("[a-z]+"), Query::All);
+    }
+
+    #[test]
+    fn regex_case_insensitive() {
+        check("(?i)hello", &["hello", "HELLO", "HeLLo"], &["help"]);
+        check("(?i)get(value|name)", &["getvalue", "GetValue", "GETNAME"], &["get value"]);
+        check("(?i)straße", &["straße", "STRAßE", "STRAẞE"], &["strasse"]);
+        check("foo(?i:bar)", &["foobar", "fooBAR"], &["FOObar"]);
+
+        // Two byte terms are looked up through their case variants
+        let query = check("(?i)fd", &["fd", "FD", "fD", "xFd", "Fdx"], &["f d", "dfx"]);
+        assert_ne!(query, Query::All);
+    }
+}
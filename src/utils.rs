@@ -1,12 +1,20 @@
 use std::time::Duration;
 use std::fs;
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::io::{BufWriter, Result};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 #[macro_export]
 macro_rules! unwrap {
     ($result: expr, $message: expr) => {
         match $result {
             Ok(val) => val,
+            // The output of the tools reading it is left alone
+            Err(err) if $crate::utils::is_quiet() => {
+                eprintln!("[-] {}: {}", $message, err);
+                std::process::exit(-1);
+            }
             Err(err) => {
                 println!("[-] {}: {}", $message, err);
                 std::process::exit(-1);
@@ -41,82 +49,207 @@ pub static _UNDERLINE:  &str = "\x1b[4m";
 
 /// The extensions to skip. These are all binary formats so even if they are not
 /// skipped, they will still not be indexed.
-pub static SKIP_EXT: [&str; 22] = [
+pub static SKIP_EXT: [&str; 23] = [
     "png", "jpg", "jpeg", "pdf",
     "pyc", "zip",  "tgz", "tar",
     "gz",   "so",  "bin", "wasm",
     "o",  "rlib", "json", "dat",
     "whl", "wav",  "pcm", "avif",
-    "rmeta", "a",
+    "rmeta", "a",  "idb",
 ];
 
-/// The max length of a line for a search match to be printed on the screen.
-/// Matches with length over this are not printed in full, but only the filename
-/// and line number.
-static MAX_LEN: usize = 100;
+/// The address of the server when none is configured and the user has no dir
+/// to keep the socket of the server in
+pub static DEFAULT_ADDR: &str = "127.0.0.1:4141";
 
-pub fn print_time_stats(msg: &str, elapsed: Duration) {
-    println!("\n==== {msg} Done ====");
-    println!("{msg} took: {:.2} ns / {:.2} us / {:.2} ms / {:.2}s",
-             elapsed.as_nanos(), elapsed.as_micros(),
-             elapsed.as_millis(), elapsed.as_secs_f64());
+/// The environment variable with the address of the server
+pub static ADDR_VAR: &str = "IDFIND_ADDR";
 
+/// Get the user's dir from the XDG variable `var`, or else `fallback` in the
+/// home dir
+fn xdg_dir(var: &str, fallback: &str) -> Option<PathBuf> {
+    std::env::var_os(var)
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(fallback)))
 }
 
+/// Get the default path of the database for the project at `project`. The
+/// databases are kept in the user's cache dir (`$XDG_CACHE_HOME` or
+/// `~/.cache`), named after the path of the project, so that the project itself
+/// is left untouched and every project gets its own database.
+pub fn default_db_path(project: &Path) -> Option<PathBuf> {
+    let cache = xdg_dir("XDG_CACHE_HOME", ".cache")?;
 
-/// Check if the file that is passed as arg contains the `input` string. It
-/// returns the number of lines on which the `input` was found
-pub fn check_file(path: &PathBuf, input: &str) -> usize {
+    Some(cache.join("idfind").join(db_name(project)))
+}
 
-    let data = match fs::read_to_string(path) {
-        Ok(data) => data,
-        Err(_)   => return 0
-    };
+/// Get the file name of the default database of `project`, which is its path
+/// with every `/` turned into a `%`. A `%` of the path itself is escaped as
+/// `%25` first, so that no two projects get the same name.
+fn db_name(project: &Path) -> String {
+    project.to_string_lossy().replace('%', "%25").replace('/', "%") + ".idb"
+}
 
-    let mut hits = String::new();
-    let mut hit_count = 0;
-    let path = match path.as_path().to_str() {
-        Some(path) => path,
-        None       => return 0
-    };
+/// Read the value of `key` from the user's config file, which is
+/// `$XDG_CONFIG_HOME/idfind/config` or `~/.config/idfind/config`. The file has
+/// a `key = value` pair per line, and `#` starts a comment.
+pub fn config_value(key: &str) -> Option<String> {
+    let path = xdg_dir("XDG_CONFIG_HOME", ".config")?.join("idfind").join("config");
+    let data = fs::read_to_string(path).ok()?;
+
+    data.lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .filter_map(|line| line.split_once('='))
+        .find(|(name, _)| name.trim() == key)
+        .map(|(_, value)| value.trim().to_string())
+}
 
-    let jstr = format!("{_FAIL}{_BOLD}{input}{_ENDC}");
+/// Get the address of the server to listen on or to connect to. The first one
+/// set of `addr`, `$IDFIND_ADDR` and the `addr` of the config file is used, and
+/// the user's own socket otherwise.
+pub fn server_addr(addr: Option<&str>) -> String {
+    addr.map(str::to_string)
+        .or_else(|| std::env::var(ADDR_VAR).ok().filter(|addr| !addr.is_empty()))
+        .or_else(|| config_value("addr"))
+        .unwrap_or_else(default_addr)
+}
 
-    data.split("\n").enumerate().for_each(|(lno, line)| {
-        if line.find(input).is_some() {
-            let data = if line.len() > MAX_LEN {
-                // "*[long matching line]*"
-                String::from("*[long matching line]*")
-            } else {
-                let splits = line.split(input).collect::<Vec<_>>();
-                splits.join(&jstr)
-            };
-            // hits += &format!("{_FAIL}{:04}{_ENDC}:   {}\n", lno+1, data);
-            // hits += &format!("{_HEADER}{path}{_ENDC}:{_OKBLUE}{}{_ENDC}:   {}\n", lno+1, &data);
-            hits += &format!("{path}:{}:   {}\n", lno+1, &data);
-            hit_count += 1;
-        }
+/// Get the default address of the server, which is a Unix socket in the user's
+/// runtime dir (`$XDG_RUNTIME_DIR` or else `~/.cache`), so that only the user
+/// can reach it
+fn default_addr() -> String {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| xdg_dir("XDG_CACHE_HOME", ".cache"))
+        .map(|dir| format!("unix:{}", dir.join("idfind").join("server.sock").display()))
+        .unwrap_or_else(|| DEFAULT_ADDR.to_string())
+}
+
+/// Find the default database of the project that `path` is part of, by looking
+/// for the database of `path` and then of each of its parents
+pub fn find_default_db(path: &Path) -> Option<PathBuf> {
+    path.ancestors()
+        .filter_map(default_db_path)
+        .find(|db| db.is_file())
+}
+
+/// Take the advisory lock of the database at `path`, waiting for whoever holds
+/// it to be done. The lock is held for as long as the returned file is open.
+pub fn lock_db(path: &Path) -> Result<fs::File> {
+
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".lock");
+
+    let lock = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(name)?;
+
+    if lock.try_lock().is_err() {
+        println!("Waiting for another idfind to finish writing {}", path.display());
+        lock.lock()?;
+    }
+
+    Ok(lock)
+}
+
+/// Write the file at `path` by writing to a temporary file next to it with
+/// `write` and then renaming it over `path`. An interrupted write leaves `path`
+/// untouched.
+pub fn write_atomic<F>(path: &Path, write: F) -> Result<()>
+    where F: FnOnce(&mut BufWriter<fs::File>) -> Result<()> {
+
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".{}.tmp", std::process::id()));
+    let tmp = path.with_file_name(name);
+
+    let result = fs::File::create(&tmp).and_then(|file| {
+        let mut out = BufWriter::new(file);
+        write(&mut out)?;
+
+        // Make sure that the data hits the disk before the file is renamed,
+        // otherwise a crash could leave an empty file behind
+        out.into_inner()?.sync_all()?;
+        fs::rename(&tmp, path)
     });
 
-    if !hits.is_empty() {
-        // hits = format!("{_HEADER}{}{_ENDC}\n",
-        //                path.as_path().to_str().unwrap()) + &hits;
-        println!("{hits}");
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
     }
 
-    hit_count
+    result
 }
 
+/// Set when the status messages would get in the way of the output, like with
+/// the JSON output
+static QUIET: AtomicBool = AtomicBool::new(false);
 
-/// Get a vector of indices for the string passed as input
-pub fn get_indices(sample: &str) -> Vec<usize> {
-    let mut idc: Vec<usize> = sample.char_indices().map(|(i, _)| i).collect();
-    idc.push(sample.len());
-    idc
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
 }
 
-/// Split a string into trigrams
-pub fn tokenize(sample: &str) -> Vec<String> {
-    let idc = get_indices(sample);
-    (0..idc.len()-3).map(|i| String::from(&sample[idc[i]..idc[i+3]])).collect()
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+pub fn print_time_stats(msg: &str, elapsed: Duration) {
+    if is_quiet() {
+        return;
+    }
+
+    println!("\n==== {msg} Done ====");
+    println!("{msg} took: {:.2} ns / {:.2} us / {:.2} ms / {:.2}s",
+             elapsed.as_nanos(), elapsed.as_micros(),
+             elapsed.as_millis(), elapsed.as_secs_f64());
+
+}
+
+
+/// Hash the contents of a file. This is the 64 bit FNV-1a hash, which unlike the
+/// std hasher is stable across builds, so it can be saved in the database.
+pub fn hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// A trigram packed into an integer. The three bytes are stored big endian in
+/// the low 24 bits, so that the integer order of trigrams is the same as the
+/// lexicographic order of their bytes.
+pub type Trigram = u32;
+
+/// Number of bytes in the n-grams that are indexed
+pub const NGRAM: usize = 3;
+
+/// Pack the first three bytes of `bytes` into a `Trigram`
+pub fn trigram(bytes: &[u8]) -> Trigram {
+    u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]])
+}
+
+/// Split a string into the byte level trigrams of all its windows
+pub fn tokenize(sample: &str) -> Vec<Trigram> {
+    sample.as_bytes().windows(NGRAM).map(trigram).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn db_names_are_distinct() {
+        assert_eq!(db_name(Path::new("/home/me/src")), "%home%me%src.idb");
+        assert_eq!(db_name(Path::new("/a/b")), "%a%b.idb");
+        assert_eq!(db_name(Path::new("/a%b")), "%a%25b.idb");
+        assert_eq!(db_name(Path::new("/a%2Fb")), "%a%252Fb.idb");
+
+        let paths = ["/a/b", "/a%b", "/a%/b", "/a/%b", "/a%25b", "/a%2Fb"];
+        let names: std::collections::HashSet<_> = paths.iter()
+            .map(|path| db_name(Path::new(path)))
+            .collect();
+        assert_eq!(names.len(), paths.len());
+    }
 }
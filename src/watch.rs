@@ -0,0 +1,47 @@
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+
+use crate::network::{Request, Stream};
+use std::time::Duration;
+
+/// How long the filesystem has to be quiet before a batch of changes is applied
+/// to the database
+pub static DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// How long the filesystem has to be quiet before the posting lists of the
+/// database are compacted, and saved to disk with `--persist`
+pub static PERSIST_DELAY: Duration = Duration::from_secs(30);
+
+/// The messages handled by the thread serving a database
+pub enum Message {
+    /// A search request whose response is to be sent on the stream
+    Search(Request, Stream),
+
+    /// Files or directories under the project root that changed on disk
+    Changed(Vec<PathBuf>),
+}
+
+/// Watch `root` recursively for changes to the files under it. Every change is
+/// sent to `tx` as a `Message::Changed`. The watch lasts as long as the
+/// returned watcher is alive.
+pub fn watch(root: &Path, tx: Sender<Message>) -> notify::Result<RecommendedWatcher> {
+
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        let Ok(event) = event else {
+            return;
+        };
+
+        // Only changes to the contents or the names of files are interesting,
+        // accesses and the like can be ignored
+        if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) |
+                                EventKind::Remove(_)) {
+            let _ = tx.send(Message::Changed(event.paths));
+        }
+    })?;
+
+    watcher.watch(root, RecursiveMode::Recursive)?;
+
+    Ok(watcher)
+}